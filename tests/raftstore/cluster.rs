@@ -25,12 +25,13 @@ use futures::Future;
 use tikv::raftstore::{Error, Result};
 use tikv::raftstore::store::*;
 use tikv::config::TiKvConfig;
-use tikv::storage::{ALL_CFS, CF_DEFAULT};
+use tikv::storage::{ALL_CFS, CF_DEFAULT, CF_RAFT, DATA_CFS};
 use super::util::*;
 use kvproto::pdpb;
 use kvproto::raft_cmdpb::*;
 use kvproto::metapb::{self, RegionEpoch};
-use kvproto::raft_serverpb::RaftMessage;
+use kvproto::raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RaftMessage,
+                             RegionLocalState, StoreIdent};
 use kvproto::errorpb::Error as PbError;
 use tikv::pd::PdClient;
 use tikv::util::{escape, rocksdb, HandyRwLock};
@@ -83,6 +84,8 @@ pub struct Cluster<T: Simulator> {
 
     pub sim: Arc<RwLock<T>>,
     pub pd_client: Arc<TestPdClient>,
+
+    pump: PumpFilter,
 }
 
 impl<T: Simulator> Cluster<T> {
@@ -102,6 +105,7 @@ impl<T: Simulator> Cluster<T> {
             engines: HashMap::new(),
             sim: sim,
             pd_client: pd_client,
+            pump: PumpFilter::default(),
         };
 
         c.create_engines(count, cfs);
@@ -186,6 +190,13 @@ impl<T: Simulator> Cluster<T> {
         self.engines[&node_id].raft_engine.clone()
     }
 
+    pub fn raft_local_state(&self, node_id: u64, region_id: u64) -> RaftLocalState {
+        self.get_raft_engine(node_id)
+            .get_msg(&keys::raft_state_key(region_id))
+            .unwrap()
+            .unwrap()
+    }
+
     pub fn send_raft_msg(&mut self, msg: RaftMessage) -> Result<()> {
         self.sim.wl().send_raft_msg(msg)
     }
@@ -733,6 +744,52 @@ impl<T: Simulator> Cluster<T> {
         }
     }
 
+    /// Hold all the raft messages sent from now on instead of delivering them,
+    /// so a test delivers them one by one in the order it chooses with
+    /// `deliver`, or in the order they were sent with `step`. Removing the
+    /// send filters delivers the rest.
+    ///
+    /// Only the delivery is under control of the test, this isn't a fully
+    /// deterministic mode: the raftstores still tick on their own timers, so
+    /// how ticks interleave with the deliveries can't be reproduced exactly.
+    /// Make the election timeout long enough for the scenario, and seed the
+    /// random filters installed alongside, see `DropPacketFilter::seed`.
+    pub fn hold_messages(&self) {
+        self.add_send_filter(CloneFilterFactory(self.pump.clone()));
+    }
+
+    /// The messages held by `hold_messages` in the order they were sent.
+    pub fn pending_messages(&self) -> Vec<RaftMessage> {
+        self.pump.pending()
+    }
+
+    /// Deliver the message to its store, removing it from the held messages.
+    /// A message that isn't held, like a crafted one, is delivered as well.
+    pub fn deliver(&mut self, msg: &RaftMessage) {
+        let msg = self.pump.take(msg).unwrap_or_else(|| msg.clone());
+        self.send_raft_msg(msg).unwrap();
+    }
+
+    /// Deliver the earliest held message and return it, so a test can pump the
+    /// messages in the order they were sent. Returns `None` if none is held.
+    pub fn step(&mut self) -> Option<RaftMessage> {
+        let msg = self.pump.pop_front();
+        if let Some(ref msg) = msg {
+            self.send_raft_msg(msg.clone()).unwrap();
+        }
+        msg
+    }
+
+    /// Remove the send and receive filters of every node, restoring a fully
+    /// connected network. Messages held back by the filters are delivered.
+    pub fn heal_network(&mut self) {
+        let mut sim = self.sim.wl();
+        for node_id in sim.get_node_ids() {
+            sim.clear_send_filters(node_id);
+            sim.clear_recv_filters(node_id);
+        }
+    }
+
     // It's similar to `ask_split`, the difference is the msg, it sends, is `Msg::SplitRegion`,
     // and `region` will not be embedded to that msg.
     // Caller must ensure that the `split_key` is in the `region`.
@@ -796,6 +853,46 @@ impl<T: Simulator> Cluster<T> {
         }
     }
 
+    /// Split at all the keys, then return every region sorted by start key once
+    /// the running nodes agree on them. Panics if the regions don't cover the
+    /// whole key space contiguously or a key isn't a region boundary.
+    pub fn must_split_at_keys(&mut self, split_keys: &[&[u8]]) -> Vec<metapb::Region> {
+        for key in split_keys {
+            let region = self.get_region(key);
+            if region.get_start_key() != *key {
+                self.must_split(&region, key);
+            }
+        }
+        self.verify_region_consistency();
+
+        let mut regions: Vec<metapb::Region> = vec![];
+        loop {
+            let start_key = regions
+                .last()
+                .map_or_else(Vec::new, |r| r.get_end_key().to_vec());
+            let region = self.get_region(&start_key);
+            assert_eq!(
+                region.get_start_key(),
+                start_key.as_slice(),
+                "gap before {:?}",
+                region
+            );
+            let end = region.get_end_key().is_empty();
+            regions.push(region);
+            if end {
+                break;
+            }
+        }
+        for key in split_keys {
+            assert!(
+                regions.iter().any(|r| r.get_start_key() == *key),
+                "{} is not a region boundary",
+                escape(key)
+            );
+        }
+        regions
+    }
+
     /// Make sure region exists on that store.
     pub fn must_region_exist(&mut self, region_id: u64, store_id: u64) {
         let mut try_cnt = 0;
@@ -846,6 +943,192 @@ impl<T: Simulator> Cluster<T> {
         }
     }
 
+    /// Get the regions a node holds according to its local region states,
+    /// tombstone peers are skipped.
+    pub fn get_local_regions(&self, node_id: u64) -> HashMap<u64, metapb::Region> {
+        let engine = self.get_engine(node_id);
+        let mut region_ids = vec![];
+        engine
+            .scan_cf(
+                CF_RAFT,
+                keys::REGION_META_MIN_KEY,
+                keys::REGION_META_MAX_KEY,
+                false,
+                &mut |key, _| {
+                    let (region_id, suffix) = keys::decode_region_meta_key(key)?;
+                    if suffix == keys::REGION_STATE_SUFFIX {
+                        region_ids.push(region_id);
+                    }
+                    Ok(true)
+                },
+            )
+            .unwrap();
+
+        let mut regions = HashMap::new();
+        for region_id in region_ids {
+            let mut state: RegionLocalState = engine
+                .get_msg_cf(CF_RAFT, &keys::region_state_key(region_id))
+                .unwrap()
+                .unwrap();
+            if state.get_state() != PeerState::Tombstone {
+                regions.insert(region_id, state.take_region());
+            }
+        }
+        regions
+    }
+
+    /// Get the id of the store running on the node from its store ident,
+    /// so filters can target the store of a node.
+    pub fn get_store_id(&self, node_id: u64) -> u64 {
+        let ident: StoreIdent = self.get_engine(node_id)
+            .get_msg(keys::STORE_IDENT_KEY)
+            .unwrap()
+            .unwrap();
+        ident.get_store_id()
+    }
+
+    /// Get the peer of the region on the node, if the node holds it.
+    pub fn get_peer_on_node(&self, region_id: u64, node_id: u64) -> Option<metapb::Peer> {
+        let store_id = self.get_store_id(node_id);
+        self.get_local_regions(node_id)
+            .get(&region_id)
+            .and_then(|r| r.get_peers().iter().find(|p| p.get_store_id() == store_id))
+            .cloned()
+    }
+
+    fn check_region_consistency(&self) -> result::Result<(), String> {
+        let mut views: HashMap<u64, (u64, metapb::Region)> = HashMap::new();
+        for node_id in self.sim.rl().get_node_ids() {
+            for (region_id, region) in self.get_local_regions(node_id) {
+                if let Some(&(other_id, ref other)) = views.get(&region_id) {
+                    if other.get_start_key() != region.get_start_key() ||
+                        other.get_end_key() != region.get_end_key() ||
+                        other.get_region_epoch() != region.get_region_epoch()
+                    {
+                        return Err(format!(
+                            "region {} differs, node {}: {:?}, node {}: {:?}",
+                            region_id,
+                            other_id,
+                            other,
+                            node_id,
+                            region
+                        ));
+                    }
+                    continue;
+                }
+                views.insert(region_id, (node_id, region));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure all running nodes agree on the range and epoch of every region
+    /// they hold. Followers may apply splits later than the leader, so it retries
+    /// for a while before panicking.
+    pub fn verify_region_consistency(&self) {
+        let mut try_cnt = 0;
+        loop {
+            let err = match self.check_region_consistency() {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            if try_cnt > 250 {
+                panic!("region views are inconsistent: {}", err);
+            }
+            try_cnt += 1;
+            sleep_ms(20);
+        }
+    }
+
+    /// Get the applied index of the region on the node and all the data of the
+    /// region it holds, as (cf, key, value) in order.
+    fn get_region_data(
+        &self,
+        node_id: u64,
+        region: &metapb::Region,
+    ) -> (u64, Vec<(&'static str, Vec<u8>, Vec<u8>)>) {
+        let engine = self.get_engine(node_id);
+        let apply_state: RaftApplyState = engine
+            .get_msg_cf(CF_RAFT, &keys::apply_state_key(region.get_id()))
+            .unwrap()
+            .unwrap_or_default();
+        let (start_key, end_key) = (keys::enc_start_key(region), keys::enc_end_key(region));
+        let mut data = vec![];
+        for cf in DATA_CFS {
+            engine
+                .scan_cf(cf, &start_key, &end_key, false, &mut |key, value| {
+                    data.push((*cf, key.to_vec(), value.to_vec()));
+                    Ok(true)
+                })
+                .unwrap();
+        }
+        (apply_state.get_applied_index(), data)
+    }
+
+    fn check_data_consistency(&self) -> result::Result<(), String> {
+        let mut regions: HashMap<u64, (metapb::Region, Vec<u64>)> = HashMap::new();
+        for node_id in self.sim.rl().get_node_ids() {
+            for (region_id, region) in self.get_local_regions(node_id) {
+                regions
+                    .entry(region_id)
+                    .or_insert_with(|| (region, vec![]))
+                    .1
+                    .push(node_id);
+            }
+        }
+        for (region_id, (region, node_ids)) in regions {
+            let (first_id, rest) = node_ids.split_first().unwrap();
+            let (applied_index, data) = self.get_region_data(*first_id, &region);
+            for node_id in rest {
+                let (other_index, other_data) = self.get_region_data(*node_id, &region);
+                if other_index != applied_index {
+                    return Err(format!(
+                        "region {} applied index differs, store {}: {}, store {}: {}",
+                        region_id,
+                        first_id,
+                        applied_index,
+                        node_id,
+                        other_index
+                    ));
+                }
+                let diverged = data.iter()
+                    .zip(&other_data)
+                    .find(|&(a, b)| a != b)
+                    .map(|(a, _)| a)
+                    .or_else(|| data.get(other_data.len()))
+                    .or_else(|| other_data.get(data.len()));
+                if let Some(&(cf, ref key, _)) = diverged {
+                    return Err(format!(
+                        "region {} data diverges on store {} at {} key {}",
+                        region_id,
+                        node_id,
+                        cf,
+                        escape(key)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure all the replicas of every region have applied to the same index
+    /// and hold identical data. Snapshots and appends may still be in flight,
+    /// so it retries for a while before panicking with the diverging store.
+    pub fn must_check_consistency(&self) {
+        let mut try_cnt = 0;
+        loop {
+            let err = match self.check_data_consistency() {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            if try_cnt > 250 {
+                panic!("replicas are inconsistent: {}", err);
+            }
+            try_cnt += 1;
+            sleep_ms(20);
+        }
+    }
+
     // it's so common that we provide an API for it
     pub fn partition(&self, s1: Vec<u64>, s2: Vec<u64>) {
         self.add_send_filter(PartitionFilterFactory::new(s1, s2));
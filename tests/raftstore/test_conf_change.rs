@@ -637,3 +637,34 @@ fn test_conf_change_remove_leader() {
         "ignore remove leader"
     );
 }
+
+fn test_conf_change_drop_append<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+
+    // Half of the appends carrying the conf change are lost, raft
+    // should keep retrying until the membership change is committed.
+    cluster.add_send_filter(CloneFilterFactory(DropConfChangeFilter::new(50)));
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+
+    cluster.must_put(b"k2", b"v2");
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k1", b"v1");
+    must_get_equal(&engine_3, b"k2", b"v2");
+}
+
+#[test]
+fn test_node_conf_change_drop_append() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_conf_change_drop_append(&mut cluster);
+}
+
+#[test]
+fn test_server_conf_change_drop_append() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_conf_change_drop_append(&mut cluster);
+}
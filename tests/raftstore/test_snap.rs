@@ -470,3 +470,85 @@ fn test_server_snapshot_with_append() {
     let mut cluster = new_server_cluster(0, 4);
     test_snapshot_with_append(&mut cluster);
 }
+
+fn test_corrupted_snapshot<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    // Disable default max peer count check.
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+    cluster.must_put(b"k1", b"v1");
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+
+    // Store 3 fails to decode the first two snapshots it receives.
+    let (tx, rx) = mpsc::channel();
+    cluster
+        .sim
+        .wl()
+        .add_recv_filter(3, box CorruptSnapshotFilter::new(2, tx));
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    // But the snapshot is sent again until it gets through.
+    cluster.must_put(b"k2", b"v2");
+    let engine3 = cluster.get_engine(3);
+    must_get_equal(&engine3, b"k1", b"v1");
+    must_get_equal(&engine3, b"k2", b"v2");
+}
+
+#[test]
+fn test_node_corrupted_snapshot() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_corrupted_snapshot(&mut cluster);
+}
+
+#[test]
+fn test_server_corrupted_snapshot() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_corrupted_snapshot(&mut cluster);
+}
+
+fn test_interrupted_snapshot<T: Simulator>(cluster: &mut Cluster<T>) {
+    let pd_client = cluster.pd_client.clone();
+    // Disable default max peer count check.
+    pd_client.disable_default_rule();
+    let r1 = cluster.run_conf_change();
+    cluster.must_put(b"k1", b"v1");
+    pd_client.must_add_peer(r1, new_peer(2, 2));
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+
+    // The first snapshot to store 3 arrives broken, and the retries after it
+    // are dropped.
+    let (corrupt_tx, corrupt_rx) = mpsc::channel();
+    let (drop_tx, drop_rx) = mpsc::channel();
+    {
+        let mut sim = cluster.sim.wl();
+        sim.add_recv_filter(3, box SnapshotStreamFilter::new(1, drop_tx));
+        sim.add_recv_filter(3, box CorruptSnapshotFilter::new(1, corrupt_tx));
+    }
+    pd_client.must_add_peer(r1, new_peer(3, 3));
+    corrupt_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    drop_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let engine3 = cluster.get_engine(3);
+    must_get_none(&engine3, b"k1");
+
+    // Once the link recovers, the snapshot is sent again until it gets through.
+    cluster.sim.wl().clear_recv_filters(3);
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&engine3, b"k1", b"v1");
+    must_get_equal(&engine3, b"k2", b"v2");
+}
+
+#[test]
+fn test_node_interrupted_snapshot() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_interrupted_snapshot(&mut cluster);
+}
+
+#[test]
+fn test_server_interrupted_snapshot() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_interrupted_snapshot(&mut cluster);
+}
@@ -100,8 +100,9 @@ where
             "{:?}",
             resp
         );
-
     }
+
+    cluster.verify_region_consistency();
 }
 
 #[test]
@@ -726,3 +727,37 @@ fn test_server_quick_election_after_split() {
     let mut cluster = new_server_cluster(0, 3);
     test_quick_election_after_split(&mut cluster);
 }
+
+fn test_split_at_keys<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    let split_keys: Vec<&[u8]> = vec![b"k1", b"k3", b"k5", b"k7", b"k9"];
+    let regions = cluster.must_split_at_keys(&split_keys);
+
+    let bounds: Vec<_> = regions
+        .iter()
+        .map(|r| (r.get_start_key().to_vec(), r.get_end_key().to_vec()))
+        .collect();
+    let mut expected = vec![];
+    let mut start = b"".to_vec();
+    for key in split_keys.iter().map(|k| k.to_vec()).chain(Some(vec![])) {
+        expected.push((start, key.clone()));
+        start = key;
+    }
+    assert_eq!(bounds, expected);
+
+    // Splitting at existing boundaries changes nothing.
+    let again = cluster.must_split_at_keys(&split_keys[1..3]);
+    assert_eq!(again, regions);
+}
+
+#[test]
+fn test_node_split_at_keys() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_split_at_keys(&mut cluster);
+}
+
+#[test]
+fn test_server_split_at_keys() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_split_at_keys(&mut cluster);
+}
@@ -11,10 +11,39 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvproto::eraftpb::{Entry, MessageType};
+use kvproto::raft_serverpb::RaftMessage;
+use rand::{self, Rng};
+use tikv::raftstore::Result;
+use tikv::raftstore::store::keys;
+use tikv::raftstore::store::engine::Peekable;
+use tikv::util::HandyRwLock;
+use tikv::util::time::duration_to_ms;
+
 use super::cluster::{Cluster, Simulator};
-use super::node::new_node_cluster;
+use super::node::{new_node_cluster, NodeCluster};
 use super::server::new_server_cluster;
-use super::util::{must_get_equal, new_peer};
+use super::transport_simulate::*;
+use super::util::{must_get_equal, must_get_none, new_peer, new_put_cmd, new_request, sleep_ms};
+
+fn new_raft_message(to_store: u64, index: u64) -> RaftMessage {
+    let mut msg = RaftMessage::new();
+    msg.set_region_id(1);
+    msg.mut_to_peer().set_store_id(to_store);
+    msg.mut_message().set_msg_type(MessageType::MsgAppend);
+    msg.mut_message().set_index(index);
+    msg
+}
+
+fn indexes(msgs: &[RaftMessage]) -> Vec<u64> {
+    msgs.iter().map(|m| m.get_message().get_index()).collect()
+}
 
 fn test_partition_write<T: Simulator>(cluster: &mut Cluster<T>) {
     cluster.run();
@@ -61,3 +90,2155 @@ fn test_server_partition_write() {
     let mut cluster = new_server_cluster(0, 5);
     test_partition_write(&mut cluster);
 }
+
+#[test]
+fn test_recv_buffer_limit_drop_oldest() {
+    let filter = RecvBufferLimitFilter::new(2, Duration::from_secs(3600));
+
+    let mut msgs: Vec<_> = (1..6).map(|i| new_raft_message(2, i)).collect();
+    msgs.push(new_raft_message(3, 6));
+    filter.before(&mut msgs).unwrap();
+    assert!(msgs.is_empty());
+    assert_eq!(filter.pending(2), 2);
+    assert_eq!(filter.pending(3), 1);
+    assert_eq!(filter.overflowed(), 3);
+
+    // Only the newest two messages to store 2 survive the overflow.
+    let msgs = filter.drain();
+    let to_store_2: Vec<_> = msgs.iter()
+        .filter(|m| m.get_to_peer().get_store_id() == 2)
+        .cloned()
+        .collect();
+    assert_eq!(indexes(&to_store_2), vec![4, 5]);
+    assert_eq!(msgs.len(), 3);
+}
+
+fn test_recv_buffer_overflow<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+
+    // Heartbeats are sent far more often than the followers take them off
+    // their buffers, so the buffers overflow.
+    let filter = RecvBufferLimitFilter::new(2, Duration::from_millis(100));
+    cluster.add_send_filter(CloneFilterFactory(filter.clone()));
+    sleep_ms(300);
+    assert!(filter.overflowed() > 0);
+    for id in 1..4 {
+        assert!(filter.pending(id) <= 2);
+    }
+
+    cluster.clear_send_filters();
+    cluster.must_put(b"k2", b"v2");
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k2", b"v2");
+    }
+}
+
+#[test]
+fn test_node_recv_buffer_overflow() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_recv_buffer_overflow(&mut cluster);
+}
+
+#[test]
+fn test_flush_filters() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box RecvBufferLimitFilter::new(10, Duration::from_secs(3600)));
+
+    for i in 1..6 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    assert!(rx.try_recv().is_err());
+
+    trans.flush_filters().unwrap();
+    let delivered: Vec<_> = rx.try_iter().collect();
+    assert_eq!(indexes(&delivered), vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn test_deliver_held_on_drop() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box OutOrderFilter::new(10));
+
+    for i in 1..6 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let cloned = trans.clone();
+    drop(trans);
+    assert!(rx.try_recv().is_err());
+
+    // Held messages are delivered once the last transport is gone.
+    drop(cloned);
+    let mut delivered = indexes(&rx.try_iter().collect::<Vec<_>>());
+    delivered.sort();
+    assert_eq!(delivered, vec![1, 2, 3, 4, 5]);
+}
+
+fn test_replay_committed<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    let filter = ReplayCommittedFilter::new(30);
+    cluster.add_send_filter(CloneFilterFactory(filter.clone()));
+
+    cluster.must_put(b"k1", b"v1");
+    cluster.must_put(b"k1", b"v2");
+    cluster.must_put(b"k2", b"v1");
+    cluster.must_delete(b"k2");
+    cluster.must_put(b"k3", b"v3");
+    // Give the replayed appends some time to show up.
+    sleep_ms(500);
+    assert!(filter.replayed() > 0);
+
+    // Replaying old committed entries must not bring back overwritten
+    // or deleted values.
+    for id in 1..4 {
+        let engine = cluster.get_engine(id);
+        must_get_equal(&engine, b"k3", b"v3");
+        must_get_equal(&engine, b"k1", b"v2");
+        must_get_none(&engine, b"k2");
+    }
+}
+
+#[test]
+fn test_node_replay_committed() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_replay_committed(&mut cluster);
+}
+
+#[test]
+fn test_server_replay_committed() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_replay_committed(&mut cluster);
+}
+
+#[test]
+fn test_connect_delay() {
+    let delay = Duration::from_millis(100);
+    let filter = ConnectDelayFilter::new(delay);
+
+    let timer = Instant::now();
+    filter.before(&mut vec![new_raft_message(2, 1)]).unwrap();
+    assert!(timer.elapsed() >= delay);
+
+    let timer = Instant::now();
+    filter.before(&mut vec![new_raft_message(2, 2)]).unwrap();
+    assert!(timer.elapsed() < delay);
+
+    // A newly targeted store pays for the connection again.
+    let timer = Instant::now();
+    filter.before(&mut vec![new_raft_message(3, 3)]).unwrap();
+    assert!(timer.elapsed() >= delay);
+}
+
+#[test]
+fn test_decaying_bandwidth() {
+    let filter = BandwidthFilter::new(100_000).half_life(Duration::from_millis(200));
+    let mut msg = new_raft_message(2, 1);
+    let mut entry = Entry::new();
+    entry.set_data(vec![0; 1000]);
+    msg.mut_message().mut_entries().push(entry);
+
+    let mut last = Duration::from_secs(0);
+    for _ in 0..3 {
+        let timer = Instant::now();
+        filter.before(&mut vec![msg.clone()]).unwrap();
+        let elapsed = timer.elapsed();
+        // Throughput declines, so sending the same message takes longer.
+        assert!(elapsed > last, "{:?} <= {:?}", elapsed, last);
+        last = elapsed;
+        sleep_ms(200);
+    }
+}
+
+fn new_snapshot_message(size: usize) -> RaftMessage {
+    let mut msg = new_raft_message(2, 1);
+    msg.mut_message().set_msg_type(MessageType::MsgSnapshot);
+    msg.mut_message().mut_snapshot().set_data(vec![0; size]);
+    msg
+}
+
+#[test]
+fn test_bandwidth_scales_with_size() {
+    let filter = BandwidthFilter::new(1_000_000);
+    let timer = Instant::now();
+    filter.before(&mut vec![new_snapshot_message(100_000)]).unwrap();
+    let small = timer.elapsed();
+    let timer = Instant::now();
+    filter.before(&mut vec![new_snapshot_message(300_000)]).unwrap();
+    let large = timer.elapsed();
+    assert!(small >= Duration::from_millis(100), "{:?}", small);
+    assert!(large >= Duration::from_millis(300), "{:?}", large);
+    assert!(large > small * 2, "{:?} {:?}", small, large);
+
+    // Concurrent messages queue up on the link.
+    let filter = Arc::new(BandwidthFilter::new(1_000_000));
+    let timer = Instant::now();
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let filter = filter.clone();
+            thread::spawn(move || {
+                filter.before(&mut vec![new_snapshot_message(100_000)]).unwrap();
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    assert!(timer.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn test_low_bandwidth() {
+    // A link slower than `MIN_BANDWIDTH` keeps its bandwidth while decaying.
+    let filter = BandwidthFilter::new(512).half_life(Duration::from_millis(10));
+    sleep_ms(50);
+    let timer = Instant::now();
+    filter.before(&mut vec![new_snapshot_message(128)]).unwrap();
+    let elapsed = timer.elapsed();
+    assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+}
+
+#[test]
+fn test_jitter() {
+    let (base, spread) = (Duration::from_millis(20), Duration::from_millis(30));
+    let filter = JitterFilter::new(base, spread);
+    for _ in 0..5 {
+        let timer = Instant::now();
+        Filter::<RaftMessage>::before(&filter, &mut vec![new_raft_message(2, 1)]).unwrap();
+        assert!(timer.elapsed() >= base);
+    }
+}
+
+fn test_term_monotonicity<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    let detector = TermMonotonicityFilter::default();
+    cluster.add_send_filter(CloneFilterFactory(detector.clone()));
+
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    for id in &[2, 3, 1] {
+        cluster.must_transfer_leader(region_id, new_peer(*id, *id));
+        cluster.must_put(b"k1", b"v2");
+    }
+
+    // Force a new election by stopping the leader.
+    cluster.stop_node(1);
+    cluster.reset_leader_of_region(region_id);
+    cluster.must_put(b"k2", b"v2");
+
+    assert!(detector.violations().is_empty(), "{:?}", detector.violations());
+}
+
+#[test]
+fn test_node_term_monotonicity() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_term_monotonicity(&mut cluster);
+}
+
+#[test]
+fn test_server_term_monotonicity() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_term_monotonicity(&mut cluster);
+}
+
+fn test_wait_for_commit<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+
+    let state = cluster.raft_local_state(1, region_id);
+    // Hold messages to store 3 until the third write from now is committed.
+    let filter = WaitForCommitFilter::new(region_id, state.get_last_index() + 3, 3);
+    cluster.add_send_filter(CloneFilterFactory(filter.clone()));
+
+    cluster.must_put(b"k2", b"v2");
+    cluster.must_put(b"k3", b"v3");
+    assert!(!filter.committed());
+    must_get_none(&cluster.get_engine(3), b"k2");
+
+    cluster.must_put(b"k4", b"v4");
+    // Store 3 catches up as soon as the gating index commits.
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k4", b"v4");
+    assert!(filter.committed());
+    must_get_equal(&engine_3, b"k2", b"v2");
+}
+
+#[test]
+fn test_node_wait_for_commit() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_wait_for_commit(&mut cluster);
+}
+
+fn test_predicate_on_cluster_state<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region = cluster.get_region(b"k1");
+    cluster.must_transfer_leader(region.get_id(), new_peer(1, 1));
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k1", b"v1");
+
+    // Cut store 3 off until pd knows the region has been split.
+    let pd_client = cluster.pd_client.clone();
+    cluster.add_send_filter(CloneFilterFactory(PredicateFilter::new(move |m| {
+        m.get_to_peer().get_store_id() != 3 || pd_client.get_regions_number() > 1
+    })));
+    cluster.must_put(b"k2", b"v2");
+    sleep_ms(100);
+    must_get_none(&engine_3, b"k2");
+
+    cluster.must_split(&region, b"k3");
+    must_get_equal(&engine_3, b"k2", b"v2");
+}
+
+#[test]
+fn test_node_predicate_on_cluster_state() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_predicate_on_cluster_state(&mut cluster);
+}
+
+#[test]
+fn test_server_predicate_on_cluster_state() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_predicate_on_cluster_state(&mut cluster);
+}
+
+fn test_drop_until_committed<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k1", b"v1");
+
+    // Store 3 gets no appends until k3 is committed on the leader.
+    let leader_engine = cluster.get_engine(1);
+    let filter = DropUntilFilter::new(move || {
+        leader_engine
+            .get_value(&keys::data_key(b"k3"))
+            .unwrap()
+            .is_some()
+    });
+    cluster.add_send_filter(CloneFilterFactory(
+        ScopedFilter::new(filter.clone())
+            .to_store(3)
+            .msg_type(MessageType::MsgAppend),
+    ));
+    cluster.must_put(b"k2", b"v2");
+    sleep_ms(200);
+    must_get_none(&engine_3, b"k2");
+    assert!(!filter.satisfied());
+
+    cluster.must_put(b"k3", b"v3");
+    must_get_equal(&engine_3, b"k2", b"v2");
+    must_get_equal(&engine_3, b"k3", b"v3");
+    assert!(filter.satisfied());
+}
+
+#[test]
+fn test_node_drop_until_committed() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_drop_until_committed(&mut cluster);
+}
+
+#[test]
+fn test_server_drop_until_committed() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_drop_until_committed(&mut cluster);
+}
+
+#[test]
+fn test_reorder_between_heartbeats() {
+    let filter = HeartbeatReorderFilter::default();
+    let mut heartbeat = new_raft_message(2, 0);
+    heartbeat.mut_message().set_msg_type(MessageType::MsgHeartbeat);
+
+    let mut msgs: Vec<_> = (1..6).map(|i| new_raft_message(2, i)).collect();
+    filter.before(&mut msgs).unwrap();
+    assert!(msgs.is_empty());
+
+    let mut msgs = vec![heartbeat.clone()];
+    for i in 6..9 {
+        msgs.push(new_raft_message(2, i));
+    }
+    msgs.push(heartbeat.clone());
+    filter.before(&mut msgs).unwrap();
+    // Each heartbeat flushes what's been buffered before it and nothing else.
+    assert_eq!(msgs.len(), 10);
+    let mut first = indexes(&msgs[..5]);
+    first.sort();
+    assert_eq!(first, vec![1, 2, 3, 4, 5]);
+    assert!(is_heartbeat(&msgs[5]));
+    let mut second = indexes(&msgs[6..9]);
+    second.sort();
+    assert_eq!(second, vec![6, 7, 8]);
+    assert!(is_heartbeat(&msgs[9]));
+}
+
+fn test_reorder_between_heartbeats_write<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.add_send_filter(DefaultFilterFactory::<HeartbeatReorderFilter>::default());
+    for i in 0..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k9", b"v9");
+    }
+}
+
+#[test]
+fn test_node_reorder_between_heartbeats_write() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_reorder_between_heartbeats_write(&mut cluster);
+}
+
+fn raft_term<T: Simulator>(cluster: &Cluster<T>, store_id: u64, region_id: u64) -> u64 {
+    cluster
+        .raft_local_state(store_id, region_id)
+        .get_hard_state()
+        .get_term()
+}
+
+fn test_heartbeat_delay<T: Simulator>(cluster: &mut Cluster<T>) {
+    let election_timeout = cluster.cfg.raft_store.raft_base_tick_interval.0 *
+        cluster.cfg.raft_store.raft_election_timeout_ticks as u32;
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+    let term = raft_term(cluster, 2, region_id);
+
+    // Followers don't hear from the leader in time and start elections.
+    let filter = HeartbeatDelayFilter::new(election_timeout * 4);
+    cluster.add_send_filter(CloneFilterFactory(filter.clone()));
+    thread::sleep(election_timeout * 4);
+    assert!(raft_term(cluster, 2, region_id) > term);
+
+    // Once heartbeats are on time again, the cluster settles down.
+    filter.set_delay(Duration::from_millis(0));
+    thread::sleep(election_timeout * 6);
+    cluster.reset_leader_of_region(region_id);
+    cluster.must_put(b"k2", b"v2");
+    let term = raft_term(cluster, 2, region_id);
+    thread::sleep(election_timeout * 4);
+    assert_eq!(raft_term(cluster, 2, region_id), term);
+    must_get_equal(&cluster.get_engine(3), b"k2", b"v2");
+}
+
+#[test]
+fn test_node_heartbeat_delay() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_heartbeat_delay(&mut cluster);
+}
+
+#[test]
+fn test_server_heartbeat_delay() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_heartbeat_delay(&mut cluster);
+}
+
+/// Wait for a held message of the type from one store to another.
+fn must_pending<T: Simulator>(
+    cluster: &Cluster<T>,
+    msg_type: MessageType,
+    from: u64,
+    to: u64,
+) -> RaftMessage {
+    for _ in 0..250 {
+        let msg = cluster.pending_messages().into_iter().find(|m| {
+            m.get_message().get_msg_type() == msg_type &&
+                m.get_from_peer().get_store_id() == from &&
+                m.get_to_peer().get_store_id() == to
+        });
+        if let Some(msg) = msg {
+            return msg;
+        }
+        sleep_ms(20);
+    }
+    panic!("no {:?} from {} to {} is sent", msg_type, from, to);
+}
+
+fn test_hand_driven_election<T: Simulator>(cluster: &mut Cluster<T>) {
+    // No peer campaigns on its own during the test.
+    cluster.cfg.raft_store.raft_election_timeout_ticks = 10000;
+    cluster.run();
+    cluster.hold_messages();
+    let term = raft_term(cluster, 1, 1);
+
+    // Ask peer 1 to campaign, like the leader does when transferring leadership.
+    let mut timeout_now = RaftMessage::new();
+    timeout_now.set_region_id(1);
+    timeout_now.set_from_peer(new_peer(2, 2));
+    timeout_now.set_to_peer(new_peer(1, 1));
+    timeout_now.set_region_epoch(cluster.get_region_epoch(1));
+    timeout_now
+        .mut_message()
+        .set_msg_type(MessageType::MsgTimeoutNow);
+    timeout_now.mut_message().set_from(2);
+    timeout_now.mut_message().set_to(1);
+    cluster.deliver(&timeout_now);
+
+    let vote = must_pending(cluster, MessageType::MsgRequestVote, 1, 2);
+    assert_eq!(vote.get_message().get_term(), term + 1);
+    assert_eq!(cluster.query_leader(2, 1), None);
+    cluster.deliver(&vote);
+    let resp = must_pending(cluster, MessageType::MsgRequestVoteResponse, 2, 1);
+    assert!(!resp.get_message().get_reject());
+    cluster.deliver(&resp);
+
+    // Peer 1 wins the election, but peer 2 doesn't know until it hears from it.
+    must_pending(cluster, MessageType::MsgAppend, 1, 2);
+    assert_eq!(cluster.query_leader(1, 1), Some(new_peer(1, 1)));
+    assert_eq!(cluster.query_leader(2, 1), None);
+    assert_eq!(raft_term(cluster, 1, 1), term + 1);
+
+    // Only the new leader has sent messages since, pump the earliest one.
+    let msg = cluster.step().unwrap();
+    assert_eq!(msg.get_from_peer().get_store_id(), 1);
+
+    cluster.clear_send_filters();
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+    assert_eq!(cluster.leader_of_region(1), Some(new_peer(1, 1)));
+}
+
+#[test]
+fn test_node_hand_driven_election() {
+    let mut cluster = new_node_cluster(0, 2);
+    test_hand_driven_election(&mut cluster);
+}
+
+#[test]
+fn test_server_hand_driven_election() {
+    let mut cluster = new_server_cluster(0, 2);
+    test_hand_driven_election(&mut cluster);
+}
+
+#[test]
+fn test_time_window_filter() {
+    let filter = TimeWindowFilter::new(
+        box DropPacketFilter::new(100),
+        Duration::from_millis(200),
+        Duration::from_millis(400),
+    );
+
+    let mut msgs = vec![new_raft_message(2, 1)];
+    filter.before(&mut msgs).unwrap();
+    assert_eq!(msgs.len(), 1);
+
+    sleep_ms(250);
+    let mut msgs = vec![new_raft_message(2, 2)];
+    assert!(filter.before(&mut msgs).is_err());
+    assert!(msgs.is_empty());
+
+    sleep_ms(200);
+    let mut msgs = vec![new_raft_message(2, 3)];
+    filter.before(&mut msgs).unwrap();
+    assert_eq!(msgs.len(), 1);
+}
+
+#[test]
+fn test_time_window_delay() {
+    let delay = Duration::from_millis(100);
+    let filter = TimeWindowFilter::new(
+        box AsyncDelayFilter::new(delay),
+        Duration::from_millis(0),
+        Duration::from_millis(200),
+    );
+    let msg = new_raft_message(2, 1);
+    assert_eq!(filter.delay(&msg), Some(delay));
+
+    sleep_ms(250);
+    assert_eq!(filter.delay(&msg), None);
+}
+
+fn test_stale_follower<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k1", b"v1");
+
+    let state = cluster.raft_local_state(1, region_id);
+    let released = Arc::new(AtomicBool::new(false));
+    cluster.add_send_filter(CloneFilterFactory(FreezeCommitFilter::new(
+        region_id,
+        3,
+        state.get_hard_state().get_commit(),
+        released.clone(),
+    )));
+
+    cluster.must_put(b"k1", b"v2");
+    must_get_equal(&cluster.get_engine(1), b"k1", b"v2");
+    sleep_ms(200);
+    // Store 3 is alive but still serves the stale value.
+    must_get_equal(&engine_3, b"k1", b"v1");
+    assert_eq!(
+        cluster.query_leader(3, region_id),
+        Some(new_peer(1, 1))
+    );
+
+    released.store(true, Ordering::SeqCst);
+    must_get_equal(&engine_3, b"k1", b"v2");
+}
+
+#[test]
+fn test_node_stale_follower() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_stale_follower(&mut cluster);
+}
+
+#[test]
+fn test_server_stale_follower() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_stale_follower(&mut cluster);
+}
+
+fn test_drop_index_range<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+    let engine_3 = cluster.get_engine(3);
+    must_get_equal(&engine_3, b"k1", b"v1");
+
+    let state = cluster.raft_local_state(1, region_id);
+    let last_index = state.get_last_index();
+    let filter = DropIndexRangeFilter::new(region_id, last_index + 2, last_index + 3);
+    cluster.add_send_filter(CloneFilterFactory(
+        ScopedFilter::new(filter).to_store(3),
+    ));
+
+    cluster.must_put(b"k2", b"v2");
+    must_get_equal(&engine_3, b"k2", b"v2");
+    cluster.must_put(b"k3", b"v3");
+    cluster.must_put(b"k4", b"v4");
+    sleep_ms(200);
+    // Store 3 can't get past the dropped window.
+    must_get_none(&engine_3, b"k3");
+    let state = cluster.raft_local_state(3, region_id);
+    assert_eq!(state.get_last_index(), last_index + 1);
+
+    cluster.clear_send_filters();
+    must_get_equal(&engine_3, b"k3", b"v3");
+    must_get_equal(&engine_3, b"k4", b"v4");
+}
+
+#[test]
+fn test_node_drop_index_range() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_drop_index_range(&mut cluster);
+}
+
+#[test]
+fn test_server_drop_index_range() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_drop_index_range(&mut cluster);
+}
+
+#[test]
+fn test_drop_votes_in_term() {
+    let filter = DropVotesInTermFilter::new(5);
+    let mut msgs = vec![];
+    for &(msg_type, term) in &[
+        (MessageType::MsgRequestVote, 5),
+        (MessageType::MsgRequestVote, 6),
+        (MessageType::MsgAppend, 5),
+    ] {
+        let mut msg = new_raft_message(2, 1);
+        msg.mut_message().set_msg_type(msg_type);
+        msg.mut_message().set_term(term);
+        msgs.push(msg);
+    }
+    filter.before(&mut msgs).unwrap();
+    let kept: Vec<_> = msgs.iter().map(|m| m.get_message().get_term()).collect();
+    assert_eq!(kept, vec![6, 5]);
+    assert_eq!(msgs[1].get_message().get_msg_type(), MessageType::MsgAppend);
+}
+
+/// Record the peers that act as leader in every term.
+#[derive(Clone, Default)]
+struct LeaderRecorder {
+    leaders: Arc<Mutex<HashMap<u64, HashSet<u64>>>>,
+}
+
+impl Filter<RaftMessage> for LeaderRecorder {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut leaders = self.leaders.lock().unwrap();
+        for m in msgs.iter() {
+            let msg = m.get_message();
+            match msg.get_msg_type() {
+                MessageType::MsgAppend | MessageType::MsgHeartbeat => {
+                    leaders
+                        .entry(msg.get_term())
+                        .or_insert_with(HashSet::new)
+                        .insert(m.get_from_peer().get_id());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn test_drop_first_votes<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+
+    let filter = DropFirstNFilter::new(MessageType::MsgRequestVote, 2);
+    cluster.add_send_filter(CloneFilterFactory(filter.clone()));
+    cluster.stop_node(1);
+    // The election after the first votes are lost completes.
+    cluster.reset_leader_of_region(region_id);
+    cluster.must_put(b"k2", b"v2");
+    assert_eq!(filter.dropped(), 2);
+    must_get_equal(&cluster.get_engine(2), b"k2", b"v2");
+}
+
+#[test]
+fn test_node_drop_first_votes() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_drop_first_votes(&mut cluster);
+}
+
+#[test]
+fn test_server_drop_first_votes() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_drop_first_votes(&mut cluster);
+}
+
+#[test]
+fn test_drop_first_n_per_destination() {
+    let filter = DropFirstNFilter::new(MessageType::MsgAppend, 2).per_destination();
+    let mut msgs: Vec<_> = (1..4)
+        .flat_map(|i| vec![new_raft_message(2, i), new_raft_message(3, i)])
+        .collect();
+    filter.before(&mut msgs).unwrap();
+    let kept: Vec<_> = msgs.iter()
+        .map(|m| (m.get_to_peer().get_store_id(), m.get_message().get_index()))
+        .collect();
+    assert_eq!(kept, vec![(2, 3), (3, 3)]);
+    assert_eq!(filter.dropped(), 4);
+}
+
+fn test_duplicate_votes<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    let recorder = LeaderRecorder::default();
+    cluster.add_send_filter(CloneFilterFactory(recorder.clone()));
+    cluster.add_send_filter(CloneFilterFactory(DuplicateVoteFilter::new(100)));
+
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    // Every leader transfer triggers an election with duplicated votes.
+    for _ in 0..3 {
+        for id in 1..4 {
+            cluster.must_transfer_leader(region_id, new_peer(id, id));
+            cluster.must_put(b"k1", b"v2");
+        }
+    }
+
+    let leaders = recorder.leaders.lock().unwrap();
+    assert!(!leaders.is_empty());
+    for (term, peers) in leaders.iter() {
+        assert_eq!(peers.len(), 1, "term {} has leaders {:?}", term, peers);
+    }
+}
+
+#[test]
+fn test_node_duplicate_votes() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_duplicate_votes(&mut cluster);
+}
+
+#[test]
+fn test_server_duplicate_votes() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_duplicate_votes(&mut cluster);
+}
+
+#[test]
+fn test_proxy_hops_delay() {
+    let per_hop = Duration::from_millis(30);
+    for hops in 1..4 {
+        let filter = DelayFilter::with_hops(hops, per_hop);
+        let timer = Instant::now();
+        Filter::<RaftMessage>::before(&filter, &mut vec![new_raft_message(2, 1)]).unwrap();
+        let elapsed = timer.elapsed();
+        assert!(elapsed >= per_hop * hops, "{:?}", elapsed);
+    }
+}
+
+fn is_request_to(m: &RaftMessage, store_id: u64) -> bool {
+    m.get_to_peer().get_store_id() == store_id &&
+        Traffic::of(m.get_message().get_msg_type()) == Traffic::Request
+}
+
+fn test_hold_requests<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+
+    // Count the requests to store 2 handed to the transports and those sent
+    // after being held. A response of store 2 sent while some are still held
+    // overtakes a request handed before it.
+    let handed = Arc::new(AtomicUsize::new(0));
+    let released = Arc::new(AtomicUsize::new(0));
+    let overtaking = Arc::new(AtomicUsize::new(0));
+    let h = handed.clone();
+    cluster.add_send_filter(CloneFilterFactory(PredicateFilter::new(move |m| {
+        if is_request_to(m, 2) {
+            h.fetch_add(1, Ordering::SeqCst);
+        }
+        true
+    })));
+    cluster.add_send_filter(CloneFilterFactory(
+        HoldRequestFilter::new(2, Duration::from_millis(50)),
+    ));
+    let (h, r, o) = (handed.clone(), released.clone(), overtaking.clone());
+    cluster.add_send_filter(CloneFilterFactory(PredicateFilter::new(move |m| {
+        if is_request_to(m, 2) {
+            r.fetch_add(1, Ordering::SeqCst);
+        } else if m.get_from_peer().get_store_id() == 2 &&
+            Traffic::of(m.get_message().get_msg_type()) == Traffic::Response &&
+            h.load(Ordering::SeqCst) > r.load(Ordering::SeqCst)
+        {
+            o.fetch_add(1, Ordering::SeqCst);
+        }
+        true
+    })));
+    for i in 0..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    // Let a few rounds of heartbeats go through the holds.
+    sleep_ms(300);
+    assert!(overtaking.load(Ordering::SeqCst) > 0);
+
+    cluster.must_transfer_leader(region_id, new_peer(2, 2));
+    cluster.must_put(b"k10", b"v10");
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k10", b"v10");
+    }
+}
+
+#[test]
+fn test_node_hold_requests() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_hold_requests(&mut cluster);
+}
+
+fn test_out_order_recv<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k0", b"v0");
+    must_get_equal(&cluster.get_engine(3), b"k0", b"v0");
+
+    // Shuffle everything store 3 receives, including the appends and heartbeats.
+    cluster
+        .sim
+        .wl()
+        .add_recv_filter(3, box OutOrderFilter::new(4));
+    for i in 1..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+
+    // Messages still buffered are delivered when the filter is removed.
+    cluster.sim.wl().clear_recv_filters(3);
+    for i in 0..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        must_get_equal(&cluster.get_engine(3), key.as_bytes(), value.as_bytes());
+    }
+}
+
+#[test]
+fn test_node_out_order_recv() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_out_order_recv(&mut cluster);
+}
+
+#[test]
+fn test_server_out_order_recv() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_out_order_recv(&mut cluster);
+}
+
+fn new_peer_message(msg_type: MessageType, from: u64, to: u64, index: u64) -> RaftMessage {
+    let mut msg = new_raft_message(to, index);
+    msg.mut_message().set_msg_type(msg_type);
+    msg.set_from_peer(new_peer(from, from));
+    msg.set_to_peer(new_peer(to, to));
+    msg
+}
+
+#[test]
+fn test_causal_reorder() {
+    let filter = CausalReorderFilter::new(6);
+    let mut reordered = false;
+    for _ in 0..50 {
+        let mut msgs = vec![];
+        for &(msg_type, from, to, index) in &[
+            (MessageType::MsgAppend, 1, 2, 1),
+            (MessageType::MsgAppend, 1, 3, 2),
+            (MessageType::MsgAppendResponse, 2, 1, 3),
+            (MessageType::MsgHeartbeat, 1, 2, 4),
+            (MessageType::MsgHeartbeatResponse, 2, 1, 5),
+            (MessageType::MsgAppendResponse, 3, 1, 6),
+        ] {
+            msgs.push(new_peer_message(msg_type, from, to, index));
+        }
+        let mut out = vec![];
+        for msg in msgs {
+            let mut batch = vec![msg];
+            filter.before(&mut batch).unwrap();
+            out.extend(batch);
+        }
+        let out = indexes(&out);
+        assert_eq!(out.len(), 6);
+        let pos = |index| out.iter().position(|&i| i == index).unwrap();
+        // Every response is delivered after its request.
+        assert!(pos(1) < pos(3), "{:?}", out);
+        assert!(pos(4) < pos(5), "{:?}", out);
+        assert!(pos(2) < pos(6), "{:?}", out);
+        reordered |= out != vec![1, 2, 3, 4, 5, 6];
+    }
+    assert!(reordered);
+}
+
+/// Log the messages handed to a `CausalReorderFilter` and those it sends, in
+/// the order it sees them.
+#[derive(Clone)]
+struct CausalLog {
+    filter: CausalReorderFilter,
+    log: Arc<Mutex<Vec<(bool, RaftMessage)>>>,
+}
+
+impl Filter<RaftMessage> for CausalLog {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.extend(msgs.iter().map(|m| (false, m.clone())));
+        self.filter.before(msgs)?;
+        log.extend(msgs.iter().map(|m| (true, m.clone())));
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.filter.drain()
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.filter.reseed(node_id);
+    }
+}
+
+/// Check that every response in the log is sent after all the requests it may
+/// answer which were handed to the filter before it, returns how many
+/// responses are sent.
+fn check_causal_log(log: &[(bool, RaftMessage)]) -> usize {
+    // The requests of a region from one peer to another with a message type.
+    let (mut handed, mut sent) = (HashMap::new(), HashMap::new());
+    // The responses handed along with the requests they must wait for.
+    let mut waiting: Vec<(RaftMessage, usize)> = vec![];
+    let mut responses = 0;
+    for &(is_sent, ref m) in log {
+        let (from, to) = (m.get_from_peer().get_id(), m.get_to_peer().get_id());
+        let msg_type = m.get_message().get_msg_type();
+        let req_type = match request_of(msg_type) {
+            Some(req_type) => req_type,
+            None => {
+                let counts = if is_sent { &mut sent } else { &mut handed };
+                *counts.entry((m.get_region_id(), from, to, msg_type)).or_insert(0) += 1;
+                continue;
+            }
+        };
+        let key = (m.get_region_id(), to, from, req_type);
+        if !is_sent {
+            waiting.push((m.clone(), *handed.get(&key).unwrap_or(&0)));
+            continue;
+        }
+        let pos = waiting.iter().position(|&(ref r, _)| r == m).unwrap();
+        let (_, wait) = waiting.remove(pos);
+        assert!(*sent.get(&key).unwrap_or(&0) >= wait, "{:?} sent early", m);
+        responses += 1;
+    }
+    responses
+}
+
+fn test_causal_reorder_write<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    let log = Arc::new(Mutex::new(vec![]));
+    cluster.add_send_filter(CloneFilterFactory(CausalLog {
+        filter: CausalReorderFilter::new(4),
+        log: log.clone(),
+    }));
+    for i in 0..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    cluster.clear_send_filters();
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k9", b"v9");
+    }
+    cluster.must_check_consistency();
+
+    // The buffer is shared by all stores, so responses are held back behind
+    // the requests going the other way.
+    let responses = check_causal_log(&log.lock().unwrap());
+    assert!(responses > 0);
+}
+
+#[test]
+fn test_node_causal_reorder_write() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_causal_reorder_write(&mut cluster);
+}
+
+#[test]
+fn test_server_causal_reorder_write() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_causal_reorder_write(&mut cluster);
+}
+
+fn test_duplicate_packets<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.add_send_filter(CloneFilterFactory(DuplicatePacketFilter::new(100)));
+    for i in 0..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    let region_id = cluster.get_region_id(b"k1");
+    cluster.must_transfer_leader(region_id, new_peer(2, 2));
+    cluster.must_put(b"k10", b"v10");
+    assert_eq!(cluster.get(b"k1"), Some(b"v1".to_vec()));
+    for id in 1..4 {
+        must_get_equal(&cluster.get_engine(id), b"k10", b"v10");
+    }
+}
+
+#[test]
+fn test_node_duplicate_packets() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_duplicate_packets(&mut cluster);
+}
+
+#[test]
+fn test_server_duplicate_packets() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_duplicate_packets(&mut cluster);
+}
+
+fn test_send_errors<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.add_send_filter(CloneFilterFactory(SendErrorFilter::new(20)));
+    for i in 0..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    // The failed sends were delivered anyway, nothing is lost.
+    cluster.clear_send_filters();
+    cluster.must_put(b"k10", b"v10");
+    for id in 1..4 {
+        let engine = cluster.get_engine(id);
+        for i in 0..11 {
+            let (key, value) = (format!("k{}", i), format!("v{}", i));
+            must_get_equal(&engine, key.as_bytes(), value.as_bytes());
+        }
+    }
+}
+
+#[test]
+fn test_node_send_errors() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_send_errors(&mut cluster);
+}
+
+#[test]
+fn test_server_send_errors() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_send_errors(&mut cluster);
+}
+
+fn test_scoped_region_append<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v0");
+    let region = cluster.get_region(b"k1");
+    cluster.must_split(&region, b"k2");
+    let left = cluster.get_region(b"k1");
+    let right = cluster.get_region(b"k3");
+    cluster.must_transfer_leader(right.get_id(), new_peer(1, 1));
+
+    // Only the appends of the left region to store 3 are dropped.
+    cluster.add_send_filter(CloneFilterFactory(
+        ScopedFilter::new(DropPacketFilter::new(100))
+            .msg_type(MessageType::MsgAppend)
+            .region(left.get_id())
+            .to_store(3),
+    ));
+    cluster.must_put(b"k1", b"v1");
+    cluster.must_put(b"k3", b"v3");
+    must_get_equal(&cluster.get_engine(3), b"k3", b"v3");
+    must_get_equal(&cluster.get_engine(2), b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v0");
+
+    cluster.clear_send_filters();
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+}
+
+#[test]
+fn test_node_scoped_region_append() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_scoped_region_append(&mut cluster);
+}
+
+#[test]
+fn test_server_scoped_region_append() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_scoped_region_append(&mut cluster);
+}
+
+#[test]
+fn test_scoped_after_and_delay() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box ScopedFilter::new(SendErrorFilter::new(100)).to_store(3));
+    trans.add_filter(
+        box ScopedFilter::new(AsyncDelayFilter::new(Duration::from_millis(100))).to_store(2),
+    );
+
+    // Only the message to store 3 is reported as failed, and only the one to
+    // store 2 is delayed.
+    Channel::send(&trans, new_raft_message(2, 1)).unwrap();
+    Channel::send(&trans, new_raft_message(3, 2)).unwrap_err();
+    assert_eq!(indexes(&rx.try_iter().collect::<Vec<_>>()), vec![2]);
+    let delayed = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(indexes(&[delayed]), vec![1]);
+}
+
+#[test]
+fn test_nested_scoped_after() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let inner = ScopedFilter::new(SendErrorFilter::new(100)).msg_type(MessageType::MsgHeartbeat);
+    trans.add_filter(box ScopedFilter::new(inner).to_store(3));
+
+    // Only the heartbeat to store 3 matches both scopes, the append to store 3
+    // only matches the outer one and must not be reported as failed.
+    let mut heartbeat = new_raft_message(3, 2);
+    heartbeat.mut_message().set_msg_type(MessageType::MsgHeartbeat);
+    for _ in 0..3 {
+        Channel::send(&trans, new_raft_message(3, 1)).unwrap();
+        Channel::send(&trans, heartbeat.clone()).unwrap_err();
+        Channel::send(&trans, new_raft_message(2, 3)).unwrap();
+    }
+    assert_eq!(
+        indexes(&rx.try_iter().collect::<Vec<_>>()),
+        vec![1, 2, 3, 1, 2, 3, 1, 2, 3]
+    );
+}
+
+#[test]
+fn test_record_gaps() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let delay = Duration::from_millis(20);
+    let recorder = GapRecorderFilter::default();
+    trans.add_filter(box DelayFilter::new(delay));
+    trans.add_filter(box recorder.clone());
+
+    for i in 1..6 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    assert_eq!(rx.try_iter().count(), 5);
+
+    let gaps = recorder.gaps(0, 2);
+    assert_eq!(gaps.len(), 4);
+    for gap in gaps {
+        assert!(gap >= delay, "{:?}", gap);
+    }
+    assert!(recorder.gaps(0, 3).is_empty());
+}
+
+fn test_slow_follower_ramp<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k0", b"v0");
+    must_get_equal(&cluster.get_engine(3), b"k0", b"v0");
+
+    let step_interval = Duration::from_millis(200);
+    cluster.sim.wl().add_send_filter(
+        1,
+        box SlowFollowerRampFilter::new(
+            3,
+            Duration::from_millis(0),
+            Duration::from_millis(100),
+            step_interval,
+            Duration::from_secs(3),
+        ),
+    );
+
+    // The time store 3 takes to catch up grows along with the latency.
+    let mut lags = vec![];
+    for i in 1..4 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        let timer = Instant::now();
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+        must_get_equal(&cluster.get_engine(2), key.as_bytes(), value.as_bytes());
+        must_get_equal(&cluster.get_engine(3), key.as_bytes(), value.as_bytes());
+        lags.push(timer.elapsed());
+        sleep_ms(3 * duration_to_ms(step_interval));
+    }
+    assert!(lags[0] < lags[1] && lags[1] < lags[2], "{:?}", lags);
+}
+
+#[test]
+fn test_node_slow_follower_ramp() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_slow_follower_ramp(&mut cluster);
+}
+
+#[test]
+fn test_async_delay() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let delay = Duration::from_millis(200);
+    trans.add_filter(box AsyncDelayFilter::new(delay));
+
+    let timer = Instant::now();
+    for i in 1..4 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    // The sender isn't blocked while the messages are in flight.
+    assert!(timer.elapsed() < delay);
+    assert!(rx.try_recv().is_err());
+
+    let mut delivered = vec![];
+    for _ in 1..4 {
+        delivered.push(rx.recv_timeout(Duration::from_secs(3)).unwrap());
+    }
+    assert!(timer.elapsed() >= delay);
+    assert_eq!(indexes(&delivered), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_congestion_delay() {
+    let per_inflight = Duration::from_millis(50);
+    let filter = CongestionDelayFilter::new(per_inflight);
+    let msg = new_raft_message(2, 1);
+
+    // Latency escalates during a burst.
+    let delays: Vec<_> = (0..4)
+        .map(|_| Filter::<RaftMessage>::delay(&filter, &msg).unwrap())
+        .collect();
+    let expected: Vec<_> = (1..5).map(|n| per_inflight * n).collect();
+    assert_eq!(delays, expected);
+
+    // And recovers once the link drains.
+    thread::sleep(per_inflight * 5);
+    assert_eq!(
+        Filter::<RaftMessage>::delay(&filter, &msg),
+        Some(per_inflight)
+    );
+
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box CongestionDelayFilter::new(per_inflight));
+    let timer = Instant::now();
+    for i in 1..5 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let mut delivered = vec![];
+    for _ in 1..5 {
+        delivered.push(rx.recv_timeout(Duration::from_secs(3)).unwrap());
+    }
+    assert!(timer.elapsed() >= per_inflight * 4);
+    assert_eq!(indexes(&delivered), vec![1, 2, 3, 4]);
+}
+
+/// Delay each message for a random duration up to `max` in the background.
+struct RandomAsyncDelayFilter {
+    max: Duration,
+}
+
+impl Filter<RaftMessage> for RandomAsyncDelayFilter {
+    fn before(&self, _: &mut Vec<RaftMessage>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delay(&self, _: &RaftMessage) -> Option<Duration> {
+        let ms = rand::thread_rng().gen_range(0, duration_to_ms(self.max) + 1);
+        Some(Duration::from_millis(ms))
+    }
+}
+
+#[test]
+fn test_delayed_in_order() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let max = Duration::from_millis(100);
+    trans.add_filter(box RandomAsyncDelayFilter { max: max });
+
+    // A message delayed less than the earlier ones to the same store can't
+    // overtake them.
+    for i in 1..21 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let mut delivered = vec![];
+    for _ in 1..21 {
+        delivered.push(rx.recv_timeout(Duration::from_secs(3)).unwrap());
+    }
+    assert_eq!(indexes(&delivered), (1..21).collect::<Vec<_>>());
+
+    // Explicit reordering still applies.
+    trans.add_filter(box OutOrderFilter::new(20));
+    for i in 1..21 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let mut delivered = vec![];
+    for _ in 1..21 {
+        delivered.push(rx.recv_timeout(Duration::from_secs(3)).unwrap());
+    }
+    let mut sorted = indexes(&delivered);
+    assert_ne!(sorted, (1..21).collect::<Vec<_>>());
+    sorted.sort();
+    assert_eq!(sorted, (1..21).collect::<Vec<_>>());
+}
+
+/// Count the messages going through.
+#[derive(Clone, Default)]
+struct CountFilter {
+    count: Arc<AtomicUsize>,
+}
+
+impl Filter<RaftMessage> for CountFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        self.count.fetch_add(msgs.len(), Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_set_filters_atomically() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let sets: Vec<Vec<CountFilter>> = (0..2)
+        .map(|_| vec![CountFilter::default(), CountFilter::default()])
+        .collect();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let (trans, stop) = (trans.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    i += 1;
+                    Channel::send(&trans, new_raft_message(2, i)).unwrap();
+                }
+                i
+            })
+        })
+        .collect();
+    for i in 0..100 {
+        let filters = sets[i % 2]
+            .iter()
+            .map(|f| box f.clone() as SendFilter)
+            .collect();
+        trans.set_filters(filters);
+    }
+    stop.store(true, Ordering::SeqCst);
+    let sent: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(rx.try_iter().count() as u64, sent);
+
+    // Every message went through either both filters of a set or neither.
+    for set in &sets {
+        let counts: Vec<_> = set.iter().map(|f| f.count.load(Ordering::SeqCst)).collect();
+        assert_eq!(counts[0], counts[1]);
+    }
+}
+
+fn test_isolate_then_heal<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+
+    cluster.add_send_filter(IsolationFilterFactory::new(1));
+    cluster.reset_leader_of_region(1);
+    cluster.must_put(b"k2", b"v2");
+    let leader = cluster.leader_of_region(1).unwrap();
+    assert_ne!(leader.get_store_id(), 1);
+    must_get_none(&cluster.get_engine(1), b"k2");
+
+    // After healing, the old leader catches up with the new one.
+    cluster.clear_send_filters();
+    must_get_equal(&cluster.get_engine(1), b"k2", b"v2");
+    cluster.must_put(b"k3", b"v3");
+    must_get_equal(&cluster.get_engine(1), b"k3", b"v3");
+}
+
+#[test]
+fn test_node_isolate_then_heal() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_isolate_then_heal(&mut cluster);
+}
+
+#[test]
+fn test_server_isolate_then_heal() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_isolate_then_heal(&mut cluster);
+}
+
+fn test_heal_network<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    // Healing a network without any filters does nothing.
+    cluster.heal_network();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+
+    cluster.partition(vec![1, 2], vec![3, 4, 5]);
+    cluster
+        .sim
+        .wl()
+        .add_recv_filter(2, box DropPacketFilter::new(100));
+    cluster.reset_leader_of_region(1);
+    cluster.must_put(b"k2", b"v2");
+    must_get_none(&cluster.get_engine(1), b"k2");
+    must_get_none(&cluster.get_engine(2), b"k2");
+
+    cluster.heal_network();
+    cluster.heal_network();
+    cluster.must_put(b"k3", b"v3");
+    for id in 1..6 {
+        must_get_equal(&cluster.get_engine(id), b"k2", b"v2");
+        must_get_equal(&cluster.get_engine(id), b"k3", b"v3");
+    }
+    cluster.must_check_consistency();
+}
+
+#[test]
+fn test_node_heal_network() {
+    let mut cluster = new_node_cluster(0, 5);
+    test_heal_network(&mut cluster);
+}
+
+#[test]
+fn test_server_heal_network() {
+    let mut cluster = new_server_cluster(0, 5);
+    test_heal_network(&mut cluster);
+}
+
+fn last_index<T: Simulator>(cluster: &Cluster<T>, store_id: u64, region_id: u64) -> u64 {
+    cluster.raft_local_state(store_id, region_id).get_last_index()
+}
+
+fn test_receive_delay<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k0", b"v0");
+    must_get_equal(&cluster.get_engine(3), b"k0", b"v0");
+    let lag = last_index(cluster, 1, 1) - last_index(cluster, 3, 1);
+
+    // Only store 3 is slow, the link and the other stores are not.
+    let delay = Duration::from_millis(500);
+    cluster
+        .sim
+        .wl()
+        .add_recv_filter(3, box ReceiveDelayFilter::new(delay));
+    for i in 1..6 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    must_get_equal(&cluster.get_engine(2), b"k5", b"v5");
+    assert!(last_index(cluster, 1, 1) - last_index(cluster, 3, 1) >= lag + 5);
+
+    // Store 3 catches up once the delayed messages are processed.
+    must_get_equal(&cluster.get_engine(3), b"k5", b"v5");
+    cluster.sim.wl().clear_recv_filters(3);
+}
+
+#[test]
+fn test_node_receive_delay() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_receive_delay(&mut cluster);
+}
+
+#[test]
+fn test_server_receive_delay() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_receive_delay(&mut cluster);
+}
+
+fn dropped_indexes(filter: SendFilter) -> Vec<u64> {
+    let (tx, _rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(filter);
+    (1..101)
+        .filter(|i| Channel::send(&trans, new_raft_message(2, *i)).is_err())
+        .collect()
+}
+
+#[test]
+fn test_seeded_drop() {
+    let dropped = dropped_indexes(box DropPacketFilter::new(30).seed(42));
+    assert!(!dropped.is_empty() && dropped.len() < 100);
+    assert_eq!(dropped, dropped_indexes(box DropPacketFilter::new(30).seed(42)));
+    // Clones continue from the same state.
+    let filter = DropPacketFilter::new(30).seed(42);
+    assert_eq!(dropped, dropped_indexes(box filter.clone()));
+    assert_eq!(dropped, dropped_indexes(box filter));
+
+    // Every node draws its own sequence, which is still reproducible.
+    let factory = CloneFilterFactory(DropPacketFilter::new(30).seed(42));
+    let on_node = |id: u64| dropped_indexes(factory.generate(id).pop().unwrap());
+    assert_ne!(on_node(1), on_node(2));
+    assert_eq!(on_node(2), on_node(2));
+}
+
+#[test]
+fn test_seeded_shuffle() {
+    let shuffled = |seed: usize| {
+        let filter = OutOrderFilter::<RaftMessage>::new(20).seed(seed);
+        let mut msgs: Vec<_> = (1..21).map(|i| new_raft_message(2, i)).collect();
+        filter.before(&mut msgs).unwrap();
+        indexes(&msgs)
+    };
+    assert_ne!(shuffled(42), (1..21).collect::<Vec<_>>());
+    assert_eq!(shuffled(42), shuffled(42));
+}
+
+#[test]
+fn test_tap() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let tap = trans.tap();
+    trans.add_filter(box ScopedFilter::new(DropPacketFilter::new(100)).to_store(3));
+
+    Channel::send(&trans, new_raft_message(2, 1)).unwrap();
+    Channel::send(&trans, new_raft_message(3, 2)).unwrap_err();
+    assert_eq!(rx.try_iter().count(), 1);
+
+    let tapped: Vec<_> = tap.try_iter().collect();
+    assert_eq!(tapped.len(), 2);
+    assert_eq!(
+        tapped.iter().map(|t| (t.to_store_id, t.dropped)).collect::<Vec<_>>(),
+        vec![(2, false), (3, true)]
+    );
+    assert!(tapped.iter().all(|t| {
+        t.direction == Direction::Send && t.msg_type == MessageType::MsgAppend
+    }));
+}
+
+#[test]
+fn test_record() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box ScopedFilter::new(DropPacketFilter::new(100)).to_store(3));
+
+    // Nothing is recorded unless asked for, and only the latest messages are
+    // kept.
+    Channel::send(&trans, new_raft_message(2, 1)).unwrap();
+    assert!(trans.trace().is_empty());
+
+    trans.record(3);
+    for i in 2..6 {
+        let _ = Channel::send(&trans, new_raft_message(2 + i % 2, i));
+    }
+    assert_eq!(rx.try_iter().count(), 3);
+    let trace: Vec<_> = trans
+        .trace()
+        .into_iter()
+        .map(|e| (e.msg.to_store_id, e.decision))
+        .collect();
+    assert_eq!(
+        trace,
+        vec![
+            (3, Decision::Dropped),
+            (2, Decision::Passed),
+            (3, Decision::Dropped),
+        ]
+    );
+
+    trans.clear_filters();
+    trans.add_filter(box DuplicatePacketFilter::new(100));
+    Channel::send(&trans, new_raft_message(2, 6)).unwrap();
+    trans.set_filters(vec![
+        box AsyncDelayFilter::new(Duration::from_millis(10)) as SendFilter,
+    ]);
+    Channel::send(&trans, new_raft_message(2, 7)).unwrap();
+    let decisions: Vec<_> = trans.trace().into_iter().map(|e| e.decision).collect();
+    assert_eq!(
+        decisions,
+        vec![Decision::Dropped, Decision::Duplicated, Decision::Delayed]
+    );
+
+    // Blocking the sender delays the message too, along with a delay given by
+    // another filter.
+    let (block, delay) = (Duration::from_millis(20), Duration::from_millis(10));
+    trans.set_filters(vec![
+        box DelayFilter::new(block) as SendFilter,
+        box AsyncDelayFilter::new(delay),
+    ]);
+    Channel::send(&trans, new_raft_message(2, 8)).unwrap();
+    trans.set_filters(vec![box DelayFilter::new(block) as SendFilter]);
+    Channel::send(&trans, new_raft_message(2, 9)).unwrap();
+    let trace: Vec<_> = trans
+        .trace()
+        .into_iter()
+        .map(|e| (e.decision, e.delay, e.blocked))
+        .collect();
+    assert_eq!(
+        trace[1..].to_vec(),
+        vec![
+            (Decision::Delayed, Some(block + delay), block),
+            (Decision::Delayed, Some(block), block),
+        ]
+    );
+}
+
+#[test]
+fn test_replay() {
+    let run = |filter: SendFilter| {
+        let (tx, rx) = mpsc::channel();
+        let mut trans = SimulateTransport::new(tx);
+        trans.add_filter(filter);
+        trans.record(100);
+        for i in 0..100 {
+            let _ = Channel::send(&trans, new_raft_message(2 + i % 2, i));
+        }
+        (trans.trace(), indexes(&rx.try_iter().collect::<Vec<_>>()))
+    };
+
+    let (trace, delivered) = run(box DropPacketFilter::new(30));
+    assert!(delivered.len() < 90, "{:?}", delivered);
+    let (replayed, redelivered) = run(box ReplayFilter::new(trace.clone()));
+    assert_eq!(redelivered, delivered);
+    let decisions = |trace: &[TraceEntry]| -> Vec<_> {
+        trace.iter().map(|e| e.decision).collect()
+    };
+    assert_eq!(decisions(&replayed), decisions(&trace));
+
+    // Delays are replayed too, whether the sender was blocked or not.
+    let block = Duration::from_millis(10);
+    let (trace, delivered) = run(box ScopedFilter::new(DelayFilter::new(block)).to_store(2));
+    let (replayed, redelivered) = run(box ReplayFilter::new(trace.clone()));
+    assert_eq!(redelivered, delivered);
+    let delays = |trace: &[TraceEntry]| -> Vec<_> {
+        trace.iter().map(|e| (e.decision, e.delay, e.blocked)).collect()
+    };
+    assert_eq!(delays(&replayed), delays(&trace));
+    assert_eq!(trace[0].decision, Decision::Delayed);
+
+    // Messages beyond the trace pass through.
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box ReplayFilter::new(vec![]));
+    Channel::send(&trans, new_raft_message(2, 1)).unwrap();
+    assert_eq!(rx.try_iter().count(), 1);
+}
+
+#[test]
+fn test_filter_expr() {
+    // Drop if it's a vote from store 3, or an append past index 2.
+    let drop_if = |f: fn(&RaftMessage) -> bool| {
+        FilterExpr::Leaf(box PredicateFilter::new(move |m| !f(m)))
+    };
+    let expr = FilterExpr::Or(vec![
+        FilterExpr::And(vec![
+            drop_if(|m| m.get_message().get_msg_type() == MessageType::MsgRequestVote),
+            drop_if(|m| m.get_from_peer().get_store_id() == 3),
+        ]),
+        FilterExpr::And(vec![
+            drop_if(|m| m.get_message().get_msg_type() == MessageType::MsgAppend),
+            drop_if(|m| m.get_message().get_index() > 2),
+        ]),
+    ]);
+
+    let mut msgs = vec![];
+    for &(msg_type, from, index) in &[
+        (MessageType::MsgRequestVote, 3, 1),
+        (MessageType::MsgRequestVote, 2, 2),
+        (MessageType::MsgAppend, 3, 2),
+        (MessageType::MsgAppend, 2, 3),
+        (MessageType::MsgHeartbeat, 3, 4),
+    ] {
+        let mut msg = new_raft_message(1, index);
+        msg.mut_message().set_msg_type(msg_type);
+        msg.mut_from_peer().set_store_id(from);
+        msgs.push(msg);
+    }
+    expr.before(&mut msgs).unwrap();
+    assert_eq!(indexes(&msgs), vec![2, 2, 4]);
+
+    // An empty `And` drops everything, an empty `Or` nothing.
+    let mut msgs = vec![new_raft_message(1, 1)];
+    FilterExpr::And(vec![]).before(&mut msgs).unwrap_err();
+    let mut msgs = vec![new_raft_message(1, 1)];
+    FilterExpr::Or(vec![]).before(&mut msgs).unwrap();
+    assert_eq!(msgs.len(), 1);
+
+    // It works as a filter of a transport.
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box expr);
+    Channel::send(&trans, new_raft_message(2, 3)).unwrap_err();
+    Channel::send(&trans, new_raft_message(2, 1)).unwrap();
+    assert_eq!(indexes(&rx.try_iter().collect::<Vec<_>>()), vec![1]);
+
+    // Seeded leaves are reseeded with the expression.
+    let on_node = |node_id: u64| {
+        let leaf = FilterExpr::Leaf(box DropPacketFilter::new(30).seed(42));
+        let mut expr = FilterExpr::Or(vec![leaf]);
+        expr.reseed(node_id);
+        dropped_indexes(box expr)
+    };
+    assert_ne!(on_node(1), on_node(2));
+    assert_eq!(on_node(2), on_node(2));
+}
+
+#[test]
+fn test_send_outcome() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box DropPacketFilter::new(30));
+    trans.add_filter(box SendErrorFilter::new(30));
+
+    let (mut dropped, mut delivered, mut failed) = (0, 0, 0);
+    for i in 0..200 {
+        match trans.send_inspect(new_raft_message(2, i)) {
+            SendOutcome::Dropped => dropped += 1,
+            SendOutcome::Delivered => delivered += 1,
+            SendOutcome::Failed(_) => failed += 1,
+            SendOutcome::Held => panic!("no filter holds messages"),
+        }
+    }
+    assert_eq!(dropped, trans.stats().dropped);
+    assert!(dropped > 30 && dropped < 90, "{}", dropped);
+    assert!(failed > 10 && failed < 80, "{}", failed);
+    // Synthetic send errors are reported for delivered messages.
+    assert_eq!(rx.try_iter().count(), delivered + failed);
+
+    // Failures of the underlying channel are told apart from drops.
+    drop(rx);
+    trans.clear_filters();
+    match trans.send_inspect(new_raft_message(2, 0)) {
+        SendOutcome::Failed(_) => {}
+        outcome => panic!("unexpected outcome {:?}", outcome),
+    }
+}
+
+#[test]
+fn test_send_held() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let tap = trans.tap();
+    trans.record(10);
+    trans.add_filter(box PumpFilter::default());
+
+    // Messages held back by a filter are neither dropped nor delivered.
+    for i in 1..3 {
+        match trans.send_inspect(new_raft_message(2, i)) {
+            SendOutcome::Held => {}
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+    assert!(rx.try_recv().is_err());
+    assert_eq!(trans.stats(), TransportStats::default());
+    let decisions: Vec<_> = trans.trace().into_iter().map(|e| e.decision).collect();
+    assert_eq!(decisions, vec![Decision::Held, Decision::Held]);
+    assert!(tap.try_iter().all(|t| t.held && !t.dropped));
+
+    trans.clear_filters();
+    assert_eq!(indexes(&rx.try_iter().collect::<Vec<_>>()), vec![1, 2]);
+}
+
+#[test]
+fn test_pump_pop_front() {
+    let pump = PumpFilter::default();
+    let mut msgs = vec![new_raft_message(2, 1), new_raft_message(2, 1)];
+    pump.before(&mut msgs).unwrap();
+    pump.before(&mut vec![new_raft_message(3, 2)]).unwrap();
+    assert!(msgs.is_empty());
+
+    // Identical messages are taken one at a time, in the order they are held.
+    let popped: Vec<_> = (0..3).map(|_| pump.pop_front().unwrap()).collect();
+    assert_eq!(indexes(&popped), vec![1, 1, 2]);
+    assert!(pump.pop_front().is_none());
+}
+
+/// Count the appends the leader on store 1 sends to store 3 during a burst of
+/// writes, while the messages to store 3 are dropped.
+fn count_appends_to_lost_peer(
+    cluster: &mut Cluster<NodeCluster>,
+    unreachable: bool,
+    k: usize,
+) -> usize {
+    let appends = CountFilter::default();
+    let drop: SendFilter = if unreachable {
+        let router = cluster.sim.rl().get_node_router(1);
+        box UnreachableFilter::new(3, router)
+    } else {
+        box ScopedFilter::new(DropPacketFilter::new(100)).to_store(3)
+    };
+    {
+        let mut sim = cluster.sim.wl();
+        sim.add_send_filter(
+            1,
+            box ScopedFilter::new(appends.clone())
+                .to_store(3)
+                .msg_type(MessageType::MsgAppend),
+        );
+        sim.add_send_filter(1, drop);
+    }
+    for i in k..k + 10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    cluster.clear_send_filters();
+    let (key, value) = (format!("k{}", k + 9), format!("v{}", k + 9));
+    must_get_equal(&cluster.get_engine(3), key.as_bytes(), value.as_bytes());
+    appends.count.load(Ordering::SeqCst)
+}
+
+#[test]
+fn test_node_unreachable() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    cluster.must_put(b"k0", b"v0");
+    let region_id = cluster.get_region_id(b"k0");
+    cluster.must_transfer_leader(region_id, new_peer(1, 1));
+    must_get_equal(&cluster.get_engine(3), b"k0", b"v0");
+
+    let dropped = count_appends_to_lost_peer(&mut cluster, false, 1);
+    // Once told the peer is unreachable, the leader stops pipelining to it.
+    let unreachable = count_appends_to_lost_peer(&mut cluster, true, 11);
+    assert!(dropped >= 10, "{}", dropped);
+    assert!(unreachable < dropped / 2, "{} vs {}", unreachable, dropped);
+}
+
+#[test]
+fn test_node_tap() {
+    let mut cluster = new_node_cluster(0, 3);
+    cluster.run();
+    let tap = cluster.sim.rl().get_node_router(3).tap();
+    cluster.must_put(b"k1", b"v1");
+    must_get_equal(&cluster.get_engine(3), b"k1", b"v1");
+
+    let msg_types: HashSet<_> = tap.try_iter()
+        .map(|t| {
+            assert_eq!(t.direction, Direction::Recv);
+            assert_eq!(t.to_store_id, 3);
+            t.msg_type
+        })
+        .collect();
+    assert!(msg_types.contains(&MessageType::MsgAppend));
+}
+
+fn test_network_partition<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k1", b"v1");
+
+    cluster.add_send_filter(CloneFilterFactory(NetworkPartitionFilter::new(vec![1, 2])));
+
+    // The minority side, which holds the old leader, can't commit anything.
+    let region = cluster.get_region(b"k1");
+    let mut put = new_request(
+        region.get_id(),
+        region.get_region_epoch().clone(),
+        vec![new_put_cmd(b"k2", b"v2")],
+        false,
+    );
+    put.mut_header().set_peer(new_peer(1, 1));
+    let res = cluster.call_command(put, Duration::from_secs(1));
+    assert!(res.map(|r| r.get_header().has_error()).unwrap_or(true));
+
+    // While the majority side elects a new leader and serves writes.
+    cluster.reset_leader_of_region(region.get_id());
+    cluster.must_put(b"k3", b"v3");
+    assert!(cluster.leader_of_region(region.get_id()).unwrap().get_store_id() > 2);
+    for id in 3..6 {
+        must_get_equal(&cluster.get_engine(id), b"k3", b"v3");
+    }
+    for id in 1..3 {
+        must_get_none(&cluster.get_engine(id), b"k2");
+        must_get_none(&cluster.get_engine(id), b"k3");
+    }
+}
+
+#[test]
+fn test_node_network_partition() {
+    let mut cluster = new_node_cluster(0, 5);
+    test_network_partition(&mut cluster);
+}
+
+#[test]
+fn test_server_network_partition() {
+    let mut cluster = new_server_cluster(0, 5);
+    test_network_partition(&mut cluster);
+}
+
+#[test]
+fn test_scoped_traffic() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let filter = ScopedFilter::new(DropPacketFilter::new(100)).traffic(Traffic::Response);
+    trans.add_filter(box filter);
+
+    let mut resp = new_raft_message(1, 2);
+    resp.mut_message().set_msg_type(MessageType::MsgAppendResponse);
+    Channel::send(&trans, resp).unwrap_err();
+    let mut heartbeat_resp = new_raft_message(1, 2);
+    heartbeat_resp.mut_message().set_msg_type(MessageType::MsgHeartbeatResponse);
+    Channel::send(&trans, heartbeat_resp).unwrap_err();
+    Channel::send(&trans, new_raft_message(1, 3)).unwrap();
+    assert_eq!(indexes(&rx.try_iter().collect::<Vec<_>>()), vec![3]);
+}
+
+fn test_drop_append_responses<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k0", b"v0");
+
+    // Store 3 never acknowledges appends, so the leader never sees it
+    // catching up and keeps retrying, but store 3 still learns the commit
+    // index from the leader and applies the entries.
+    let appends = CountFilter::default();
+    cluster.sim.wl().add_send_filter(
+        1,
+        box ScopedFilter::new(appends.clone())
+            .msg_type(MessageType::MsgAppend)
+            .to_store(3),
+    );
+    cluster.sim.wl().add_send_filter(
+        3,
+        box ScopedFilter::new(DropPacketFilter::new(100))
+            .msg_type(MessageType::MsgAppendResponse)
+            .traffic(Traffic::Response),
+    );
+    for i in 1..5 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+        must_get_equal(&cluster.get_engine(3), key.as_bytes(), value.as_bytes());
+    }
+    sleep_ms(500);
+    assert!(appends.count.load(Ordering::SeqCst) > 4);
+}
+
+#[test]
+fn test_node_drop_append_responses() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_drop_append_responses(&mut cluster);
+}
+
+#[test]
+fn test_server_drop_append_responses() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_drop_append_responses(&mut cluster);
+}
+
+fn test_store_of_node<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_put(b"k1", b"v1");
+    let mut node_ids: Vec<_> = cluster.sim.rl().get_node_ids().into_iter().collect();
+    node_ids.sort();
+
+    let mapping: Vec<_> = node_ids
+        .iter()
+        .map(|&id| (cluster.get_store_id(id), cluster.get_peer_on_node(1, id).unwrap()))
+        .collect();
+    for &(store_id, ref peer) in &mapping {
+        assert_eq!(peer.get_store_id(), store_id);
+    }
+
+    for &id in &node_ids {
+        cluster.stop_node(id);
+        cluster.run_node(id);
+    }
+    let restarted: Vec<_> = node_ids
+        .iter()
+        .map(|&id| (cluster.get_store_id(id), cluster.get_peer_on_node(1, id).unwrap()))
+        .collect();
+    assert_eq!(mapping, restarted);
+}
+
+#[test]
+fn test_node_store_of_node() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_store_of_node(&mut cluster);
+}
+
+#[test]
+fn test_server_store_of_node() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_store_of_node(&mut cluster);
+}
+
+fn test_consistency_after_heal<T: Simulator>(cluster: &mut Cluster<T>) {
+    cluster.run();
+    cluster.must_transfer_leader(1, new_peer(1, 1));
+    cluster.must_put(b"k0", b"v0");
+
+    cluster.partition(vec![1], vec![2, 3]);
+    cluster.reset_leader_of_region(1);
+    for i in 1..10 {
+        let (key, value) = (format!("k{}", i), format!("v{}", i));
+        cluster.must_put(key.as_bytes(), value.as_bytes());
+    }
+    cluster.must_delete(b"k0");
+
+    cluster.clear_send_filters();
+    cluster.must_put(b"k10", b"v10");
+    must_get_equal(&cluster.get_engine(1), b"k10", b"v10");
+    cluster.must_check_consistency();
+}
+
+#[test]
+fn test_node_consistency_after_heal() {
+    let mut cluster = new_node_cluster(0, 3);
+    test_consistency_after_heal(&mut cluster);
+}
+
+#[test]
+fn test_server_consistency_after_heal() {
+    let mut cluster = new_server_cluster(0, 3);
+    test_consistency_after_heal(&mut cluster);
+}
+
+#[test]
+fn test_flaky_link() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let (base, jitter) = (Duration::from_millis(2), Duration::from_millis(4));
+    trans.add_filter(box NetworkPartitionFilter::new(vec![3]));
+    trans.add_filter(box FlakyLinkFilter::new(30, base, jitter));
+
+    let (mut dropped, mut delay) = (0, Duration::from_millis(0));
+    for i in 0..200 {
+        let timer = Instant::now();
+        if Channel::send(&trans, new_raft_message(2, i)).is_err() {
+            dropped += 1;
+        } else {
+            delay += timer.elapsed();
+        }
+    }
+    assert_eq!(rx.try_iter().count(), 200 - dropped);
+    assert!(dropped > 30 && dropped < 90, "{}", dropped);
+    let mean = delay / (200 - dropped) as u32;
+    assert!(mean >= base && mean < base + jitter * 2, "{:?}", mean);
+
+    // Messages across the partition are dropped without being delayed.
+    for i in 0..10 {
+        Channel::send(&trans, new_raft_message(3, i)).unwrap_err();
+    }
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_transport_stats() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    trans.add_filter(box DropPacketFilter::new(50));
+    for i in 0..1000 {
+        let _ = Channel::send(&trans, new_raft_message(2, i));
+    }
+    let stats = trans.stats();
+    assert!(stats.dropped > 400 && stats.dropped < 600, "{:?}", stats);
+    assert_eq!(rx.try_iter().count(), 1000 - stats.dropped);
+
+    trans.reset_stats();
+    assert_eq!(trans.stats(), TransportStats::default());
+    let delay = Duration::from_millis(10);
+    trans.set_filters(vec![
+        box DuplicatePacketFilter::new(100) as SendFilter,
+        box AsyncDelayFilter::new(delay),
+    ]);
+    for i in 0..10 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let stats = trans.stats();
+    assert_eq!(stats.duplicated, 10);
+    assert_eq!(stats.delayed, 20);
+    assert_eq!(stats.delay, delay * 20);
+    assert_eq!(stats.delay_p99, delay);
+    assert_eq!(stats.dropped, 0);
+
+    // Every copy dropped is counted, even if others of the batch pass.
+    assert_eq!(rx.iter().take(20).count(), 20);
+    trans.reset_stats();
+    trans.set_filters(vec![
+        box DuplicatePacketFilter::new(100) as SendFilter,
+        box DropFirstNFilter::new(MessageType::MsgAppend, 5),
+    ]);
+    for i in 0..5 {
+        let _ = Channel::send(&trans, new_raft_message(2, i));
+    }
+    assert_eq!(trans.stats().dropped, 5);
+    assert_eq!(rx.try_iter().count(), 5);
+
+    // Filters blocking the sender delay the messages as well.
+    trans.reset_stats();
+    trans.set_filters(vec![box DelayFilter::new(delay) as SendFilter]);
+    for i in 0..5 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let stats = trans.stats();
+    assert_eq!(stats.delayed, 5);
+    assert_eq!(stats.delay, delay * 5);
+    assert_eq!(stats.delay_p50, delay);
+}
+
+#[test]
+fn test_delay_percentiles() {
+    let (tx, rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let (base, spread) = (Duration::from_millis(10), Duration::from_millis(40));
+    trans.add_filter(box DropPacketFilter::new(20));
+    trans.add_filter(box JitterFilter::new(base, spread).background());
+    for i in 0..500 {
+        let _ = Channel::send(&trans, new_raft_message(2, i));
+    }
+    let stats = trans.stats();
+    // Dropped messages aren't delayed.
+    assert_eq!(stats.delayed, 500 - stats.dropped);
+    assert!(
+        stats.delay_p50 > base + spread / 4 && stats.delay_p50 < base + spread * 3 / 4,
+        "{:?}",
+        stats
+    );
+    assert!(
+        stats.delay_p99 >= base + spread * 9 / 10 && stats.delay_p99 <= base + spread,
+        "{:?}",
+        stats
+    );
+    for _ in 0..stats.delayed {
+        rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    }
+}
+
+#[test]
+fn test_delay_percentiles_sampled() {
+    let (tx, _rx) = mpsc::channel();
+    let mut trans = SimulateTransport::new(tx);
+    let max = Duration::from_millis(40);
+    trans.add_filter(box RandomAsyncDelayFilter { max: max });
+    // Far more messages are delayed than the samples kept.
+    for i in 0..10000 {
+        Channel::send(&trans, new_raft_message(2, i)).unwrap();
+    }
+    let stats = trans.stats();
+    assert_eq!(stats.delayed, 10000);
+    assert!(
+        stats.delay_p50 > max / 4 && stats.delay_p50 < max * 3 / 4,
+        "{:?}",
+        stats
+    );
+    assert!(stats.delay_p99 >= max * 9 / 10, "{:?}", stats);
+}
@@ -12,19 +12,22 @@
 // limitations under the License.
 
 use kvproto::raft_serverpb::RaftMessage;
-use kvproto::eraftpb::MessageType;
+use kvproto::eraftpb::{EntryType, MessageType};
 use tikv::raftstore::{Error, Result};
 use tikv::raftstore::store::{Msg as StoreMsg, SignificantMsg, Transport};
 use tikv::server::transport::*;
 use tikv::server::StoreAddrResolver;
 use tikv::util::{transport, Either, HandyRwLock};
+use tikv::util::time::{duration_to_ms, duration_to_nanos, duration_to_sec};
 
-use rand;
-use std::collections::HashMap;
+use protobuf::Message;
+use rand::{self, Rng, SeedableRng, StdRng};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex, RwLock};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::cell::Cell;
 use std::marker::PhantomData;
-use std::{thread, time, usize};
+use std::{cmp, mem, thread, time, u32, usize};
 use std::sync::atomic::*;
 
 pub trait Channel<M>: Send + Clone {
@@ -49,6 +52,12 @@ where
     }
 }
 
+impl<M: Send> Channel<M> for Sender<M> {
+    fn send(&self, m: M) -> Result<()> {
+        Sender::send(self, m).map_err(|e| box_err!("failed to send: {:?}", e))
+    }
+}
+
 impl Channel<StoreMsg> for ServerRaftStoreRouter {
     fn send(&self, m: StoreMsg) -> Result<()> {
         RaftStoreRouter::try_send(self, m)
@@ -78,564 +87,3073 @@ pub trait Filter<M>: Send + Sync {
     fn after(&self, res: Result<()>) -> Result<()> {
         res
     }
+    /// `before_scoped` runs `before` and tells whether the filter applied to
+    /// the messages. The transport hands it back to `after_scoped` for the same
+    /// send, so a filter scoped to some messages only runs `after` for the sends
+    /// it applied to, see `ScopedFilter`.
+    fn before_scoped(&self, msgs: &mut Vec<M>) -> (Result<()>, bool) {
+        (self.before(msgs), true)
+    }
+    /// `after_scoped` runs `after` if the filter applied to the send.
+    fn after_scoped(&self, applied: bool, res: Result<()>) -> Result<()> {
+        if applied {
+            return self.after(res);
+        }
+        res
+    }
+    /// `drain` takes the messages held back by the filter,
+    /// so that they can be delivered instead of getting lost.
+    fn drain(&self) -> Vec<M> {
+        vec![]
+    }
+    /// `delay` returns how long the message should be held before it's
+    /// delivered by a background thread, so the sender isn't blocked.
+    fn delay(&self, _: &M) -> Option<time::Duration> {
+        None
+    }
+    /// `reseed` is run on the clone of a filter installed on the node, so that
+    /// a seeded filter draws a different sequence of numbers on every node.
+    fn reseed(&mut self, _: u64) {}
 }
 
 pub type SendFilter = Box<Filter<RaftMessage>>;
 pub type RecvFilter = Box<Filter<StoreMsg>>;
 
-#[derive(Clone)]
+/// The random number generator of a filter. Numbers are drawn from the thread
+/// local generator unless a seed is given. With a seed, the same sequence of
+/// messages is always handled in the same way, so failures can be reproduced.
+#[derive(Default)]
+struct FilterRng {
+    seed: Option<usize>,
+    rng: Option<Mutex<StdRng>>,
+}
+
+impl FilterRng {
+    fn seeded(seed: usize) -> FilterRng {
+        FilterRng {
+            seed: Some(seed),
+            rng: Some(Mutex::new(StdRng::from_seed(&[seed]))),
+        }
+    }
+
+    /// Derive the generator from the seed and the node, so the clones on
+    /// different nodes don't draw the same numbers.
+    fn reseed(&mut self, node_id: u64) {
+        if let Some(seed) = self.seed {
+            self.rng = Some(Mutex::new(StdRng::from_seed(&[seed, node_id as usize])));
+        }
+    }
+
+    fn next_u32(&self) -> u32 {
+        match self.rng {
+            Some(ref rng) => rng.lock().unwrap().next_u32(),
+            None => rand::random(),
+        }
+    }
+
+    fn shuffle<T>(&self, values: &mut [T]) {
+        match self.rng {
+            Some(ref rng) => rng.lock().unwrap().shuffle(values),
+            None => rand::thread_rng().shuffle(values),
+        }
+    }
+}
+
+impl Clone for FilterRng {
+    fn clone(&self) -> FilterRng {
+        FilterRng {
+            seed: self.seed,
+            rng: self.rng
+                .as_ref()
+                .map(|rng| Mutex::new(rng.lock().unwrap().clone())),
+        }
+    }
+}
+
+/// Drop messages at the given rate. With a seed, the same sequence of
+/// messages is always dropped in the same way, so failures can be reproduced.
 pub struct DropPacketFilter {
     rate: u32,
+    rng: FilterRng,
 }
 
 impl DropPacketFilter {
     pub fn new(rate: u32) -> DropPacketFilter {
-        DropPacketFilter { rate: rate }
+        DropPacketFilter {
+            rate: rate,
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> DropPacketFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
     }
 }
 
 impl<M> Filter<M> for DropPacketFilter {
     fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
-        msgs.retain(|_| rand::random::<u32>() % 100u32 >= self.rate);
+        msgs.retain(|_| self.rng.next_u32() % 100u32 >= self.rate);
         check_messages(msgs)
     }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
 }
 
-#[derive(Clone)]
-pub struct DelayFilter {
-    duration: time::Duration,
+impl Clone for DropPacketFilter {
+    fn clone(&self) -> DropPacketFilter {
+        DropPacketFilter {
+            rate: self.rate,
+            rng: self.rng.clone(),
+        }
+    }
 }
 
-impl DelayFilter {
-    pub fn new(duration: time::Duration) -> DelayFilter {
-        DelayFilter { duration: duration }
+/// Report a failure for successfully sent messages at the given rate. The
+/// messages are still delivered, only the sender is told otherwise.
+pub struct SendErrorFilter {
+    rate: u32,
+    rng: FilterRng,
+}
+
+impl SendErrorFilter {
+    pub fn new(rate: u32) -> SendErrorFilter {
+        SendErrorFilter {
+            rate: rate,
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> SendErrorFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
     }
 }
 
-impl<M> Filter<M> for DelayFilter {
+impl<M> Filter<M> for SendErrorFilter {
     fn before(&self, _: &mut Vec<M>) -> Result<()> {
-        thread::sleep(self.duration);
         Ok(())
     }
-}
 
-pub struct SimulateTransport<M, C: Channel<M>> {
-    filters: Arc<RwLock<Vec<Box<Filter<M>>>>>,
-    ch: Arc<Mutex<C>>,
+    fn after(&self, res: Result<()>) -> Result<()> {
+        if res.is_ok() && self.rng.next_u32() % 100u32 < self.rate {
+            return Err(box_err!("injected send error"));
+        }
+        res
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
 }
 
-impl<M, C: Channel<M>> SimulateTransport<M, C> {
-    pub fn new(ch: C) -> SimulateTransport<M, C> {
-        SimulateTransport {
-            filters: Arc::new(RwLock::new(vec![])),
-            ch: Arc::new(Mutex::new(ch)),
+impl Clone for SendErrorFilter {
+    fn clone(&self) -> SendErrorFilter {
+        SendErrorFilter {
+            rate: self.rate,
+            rng: self.rng.clone(),
         }
     }
+}
 
-    pub fn clear_filters(&mut self) {
-        self.filters.wl().clear();
+/// Send a copy of each message again at the given rate,
+/// simulating retransmission by the network.
+pub struct DuplicatePacketFilter {
+    rate: u32,
+    rng: FilterRng,
+}
+
+impl DuplicatePacketFilter {
+    pub fn new(rate: u32) -> DuplicatePacketFilter {
+        DuplicatePacketFilter {
+            rate: rate,
+            rng: FilterRng::default(),
+        }
     }
 
-    pub fn add_filter(&mut self, filter: Box<Filter<M>>) {
-        self.filters.wl().push(filter);
+    pub fn seed(mut self, seed: usize) -> DuplicatePacketFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
     }
 }
 
-impl<M, C: Channel<M>> Channel<M> for SimulateTransport<M, C> {
-    fn send(&self, msg: M) -> Result<()> {
-        let mut taken = 0;
-        let mut msgs = vec![msg];
-        let filters = self.filters.rl();
-        let mut res = Ok(());
-        for filter in filters.iter() {
-            taken += 1;
-            res = filter.before(&mut msgs);
-            if res.is_err() {
-                break;
-            }
+impl Clone for DuplicatePacketFilter {
+    fn clone(&self) -> DuplicatePacketFilter {
+        DuplicatePacketFilter {
+            rate: self.rate,
+            rng: self.rng.clone(),
         }
-        if res.is_ok() {
-            for msg in msgs {
-                res = self.ch.lock().unwrap().send(msg);
-                if res.is_err() {
-                    break;
-                }
+    }
+}
+
+impl<M: Clone> Filter<M> for DuplicatePacketFilter {
+    fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
+        let mut to_send = Vec::with_capacity(msgs.len() * 2);
+        for m in msgs.drain(..) {
+            if self.rng.next_u32() % 100u32 < self.rate {
+                to_send.push(m.clone());
             }
+            to_send.push(m);
         }
-        for filter in filters[..taken].iter().rev() {
-            res = filter.after(res);
-        }
-        res
+        *msgs = to_send;
+        Ok(())
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
     }
 }
 
-impl<M, C: Channel<M>> Clone for SimulateTransport<M, C> {
-    fn clone(&self) -> SimulateTransport<M, C> {
-        SimulateTransport {
-            filters: self.filters.clone(),
-            ch: self.ch.clone(),
+/// Drop `MsgAppend`s carrying any conf change entry at the given rate,
+/// so membership changes have to be committed through retries.
+#[derive(Clone)]
+pub struct DropConfChangeFilter {
+    rate: u32,
+    rng: FilterRng,
+}
+
+impl DropConfChangeFilter {
+    pub fn new(rate: u32) -> DropConfChangeFilter {
+        DropConfChangeFilter {
+            rate: rate,
+            rng: FilterRng::default(),
         }
     }
+
+    pub fn seed(mut self, seed: usize) -> DropConfChangeFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
 }
 
-impl<C: Channel<RaftMessage>> Transport for SimulateTransport<RaftMessage, C> {
-    fn send(&self, m: RaftMessage) -> Result<()> {
-        Channel::send(self, m)
+impl Filter<RaftMessage> for DropConfChangeFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            let msg = m.get_message();
+            if msg.get_msg_type() != MessageType::MsgAppend ||
+                !msg.get_entries()
+                    .iter()
+                    .any(|e| e.get_entry_type() == EntryType::EntryConfChange)
+            {
+                return true;
+            }
+            self.rng.next_u32() % 100u32 >= self.rate
+        });
+        check_messages(msgs)
     }
 
-    fn flush(&mut self) {
-        self.ch.lock().unwrap().flush();
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
     }
 }
 
-impl<C: Channel<StoreMsg>> RaftStoreRouter for SimulateTransport<StoreMsg, C> {
-    fn send(&self, m: StoreMsg) -> Result<()> {
-        Channel::send(self, m)
+thread_local! {
+    // How long the filters have blocked the current thread, see `block_sender`.
+    static BLOCKED: Cell<time::Duration> = Cell::new(time::Duration::from_millis(0))
+}
+
+/// Block the sending thread for the duration, like a filter modeling a slow
+/// link does in `before`. Unlike a plain sleep, the time is counted as a delay
+/// in the stats of the transport.
+pub fn block_sender(duration: time::Duration) {
+    thread::sleep(duration);
+    BLOCKED.with(|b| b.set(b.get() + duration));
+}
+
+#[derive(Clone)]
+pub struct DelayFilter {
+    duration: time::Duration,
+}
+
+impl DelayFilter {
+    pub fn new(duration: time::Duration) -> DelayFilter {
+        DelayFilter { duration: duration }
     }
 
-    fn try_send(&self, m: StoreMsg) -> Result<()> {
-        Channel::send(self, m)
+    /// Models messages going through `hops` proxies, each of which adds
+    /// `per_hop` latency to every message.
+    pub fn with_hops(hops: u32, per_hop: time::Duration) -> DelayFilter {
+        DelayFilter::new(per_hop * hops)
     }
+}
 
-    fn significant_send(&self, m: SignificantMsg) -> Result<()> {
-        self.ch.lock().unwrap().significant_send(m)
+impl<M> Filter<M> for DelayFilter {
+    fn before(&self, _: &mut Vec<M>) -> Result<()> {
+        block_sender(self.duration);
+        Ok(())
     }
 }
 
-pub trait FilterFactory {
-    fn generate(&self, node_id: u64) -> Vec<SendFilter>;
+/// Delay each message for a random duration in `[base, base + spread]`.
+#[derive(Clone)]
+pub struct JitterFilter {
+    base: time::Duration,
+    spread: time::Duration,
+    background: bool,
+    rng: FilterRng,
 }
 
-#[derive(Default)]
-pub struct DefaultFilterFactory<F: Filter<RaftMessage> + Default>(PhantomData<F>);
+impl JitterFilter {
+    pub fn new(base: time::Duration, spread: time::Duration) -> JitterFilter {
+        JitterFilter {
+            base: base,
+            spread: spread,
+            background: false,
+            rng: FilterRng::default(),
+        }
+    }
 
-impl<F: Filter<RaftMessage> + Default + 'static> FilterFactory for DefaultFilterFactory<F> {
-    fn generate(&self, _: u64) -> Vec<SendFilter> {
-        vec![Box::new(F::default())]
+    pub fn seed(mut self, seed: usize) -> JitterFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
     }
-}
 
-pub struct CloneFilterFactory<F: Filter<RaftMessage> + Clone>(pub F);
+    /// Deliver the messages in the background like `AsyncDelayFilter`
+    /// instead of blocking the sender.
+    pub fn background(mut self) -> JitterFilter {
+        self.background = true;
+        self
+    }
 
-impl<F: Filter<RaftMessage> + Clone + 'static> FilterFactory for CloneFilterFactory<F> {
-    fn generate(&self, _: u64) -> Vec<SendFilter> {
-        vec![Box::new(self.0.clone())]
+    fn jitter(&self) -> time::Duration {
+        let spread_ms = duration_to_ms(self.spread);
+        let jitter = u64::from(self.rng.next_u32()) % (spread_ms + 1);
+        self.base + time::Duration::from_millis(jitter)
     }
 }
 
-struct PartitionFilter {
-    node_ids: Vec<u64>,
-}
+impl<M> Filter<M> for JitterFilter {
+    fn before(&self, _: &mut Vec<M>) -> Result<()> {
+        if !self.background {
+            block_sender(self.jitter());
+        }
+        Ok(())
+    }
 
-impl Filter<RaftMessage> for PartitionFilter {
-    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
-        msgs.retain(|m| !self.node_ids.contains(&m.get_to_peer().get_store_id()));
-        check_messages(msgs)
+    fn delay(&self, _: &M) -> Option<time::Duration> {
+        if self.background {
+            Some(self.jitter())
+        } else {
+            None
+        }
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
     }
 }
 
-pub struct PartitionFilterFactory {
-    s1: Vec<u64>,
-    s2: Vec<u64>,
+/// Delay each message for the given duration without blocking the sender,
+/// the message is delivered by the background thread of the transport.
+#[derive(Clone)]
+pub struct AsyncDelayFilter {
+    duration: time::Duration,
 }
 
-impl PartitionFilterFactory {
-    pub fn new(s1: Vec<u64>, s2: Vec<u64>) -> PartitionFilterFactory {
-        PartitionFilterFactory { s1: s1, s2: s2 }
+impl AsyncDelayFilter {
+    pub fn new(duration: time::Duration) -> AsyncDelayFilter {
+        AsyncDelayFilter { duration: duration }
     }
 }
 
-impl FilterFactory for PartitionFilterFactory {
-    fn generate(&self, node_id: u64) -> Vec<SendFilter> {
-        if self.s1.contains(&node_id) {
-            return vec![
-                box PartitionFilter {
-                    node_ids: self.s2.clone(),
-                },
-            ];
-        }
-        return vec![
-            box PartitionFilter {
-                node_ids: self.s1.clone(),
-            },
-        ];
+impl<M> Filter<M> for AsyncDelayFilter {
+    fn before(&self, _: &mut Vec<M>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delay(&self, _: &M) -> Option<time::Duration> {
+        Some(self.duration)
     }
 }
 
-pub struct IsolationFilterFactory {
-    node_id: u64,
+/// The sending side of the background thread delivering delayed messages.
+struct Delayer<M> {
+    tx: Sender<(time::Instant, M)>,
+    // The latest due time of the messages delayed to each store.
+    last_due: HashMap<u64, time::Instant>,
 }
 
-impl IsolationFilterFactory {
-    pub fn new(node_id: u64) -> IsolationFilterFactory {
-        IsolationFilterFactory { node_id: node_id }
+/// Delay the raft messages a store receives without blocking the sender, like
+/// a store slow to process inbound messages. Unlike `AsyncDelayFilter`, the
+/// other messages to the store, like raft commands, are not delayed.
+#[derive(Clone)]
+pub struct ReceiveDelayFilter {
+    duration: time::Duration,
+}
+
+impl ReceiveDelayFilter {
+    pub fn new(duration: time::Duration) -> ReceiveDelayFilter {
+        ReceiveDelayFilter { duration: duration }
     }
 }
 
-impl FilterFactory for IsolationFilterFactory {
-    fn generate(&self, node_id: u64) -> Vec<SendFilter> {
-        if node_id == self.node_id {
-            return vec![box DropPacketFilter { rate: 100 }];
+impl Filter<StoreMsg> for ReceiveDelayFilter {
+    fn before(&self, _: &mut Vec<StoreMsg>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delay(&self, m: &StoreMsg) -> Option<time::Duration> {
+        match *m {
+            StoreMsg::RaftMessage(_) => Some(self.duration),
+            _ => None,
         }
-        vec![
-            box PartitionFilter {
-                node_ids: vec![self.node_id],
-            },
-        ]
     }
 }
 
-#[derive(Clone, Copy)]
-pub enum Direction {
-    Recv,
-    Send,
-    Both,
+/// Deliver the delayed messages once they are due. Pending messages are
+/// delivered immediately after all the transports are dropped.
+fn deliver_delayed<M, C: Channel<M>>(rx: Receiver<(time::Instant, M)>, ch: Arc<Mutex<C>>) {
+    let mut pending: Vec<(time::Instant, M)> = vec![];
+    loop {
+        let next = pending.iter().map(|&(t, _)| t).min();
+        let res = match next {
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(t) => {
+                let now = time::Instant::now();
+                let timeout = if t > now {
+                    t - now
+                } else {
+                    time::Duration::from_millis(0)
+                };
+                rx.recv_timeout(timeout)
+            }
+        };
+        let disconnected = match res {
+            Ok(delayed) => {
+                pending.push(delayed);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        let now = time::Instant::now();
+        pending.sort_by_key(|&(t, _)| t);
+        let due = if disconnected {
+            pending.len()
+        } else {
+            pending.iter().take_while(|&&(t, _)| t <= now).count()
+        };
+        let ch = ch.lock().unwrap();
+        for (_, msg) in pending.drain(..due) {
+            if let Err(e) = ch.send(msg) {
+                debug!("failed to deliver delayed message: {:?}", e);
+            }
+        }
+        if disconnected {
+            return;
+        }
+    }
 }
 
-impl Direction {
-    pub fn is_recv(&self) -> bool {
-        match *self {
-            Direction::Recv | Direction::Both => true,
-            Direction::Send => false,
-        }
+/// A copy of a raft message going through a `SimulateTransport`,
+/// see `SimulateTransport::tap`.
+#[derive(Clone, Debug)]
+pub struct TappedMessage {
+    pub direction: Direction,
+    pub region_id: u64,
+    pub from_store_id: u64,
+    pub to_store_id: u64,
+    pub msg_type: MessageType,
+    pub dropped: bool,
+    pub held: bool,
+}
+
+/// `Tap` extracts what is reported to the taps of a transport from a message.
+pub trait Tap {
+    fn tapped(&self) -> Option<TappedMessage>;
+}
+
+fn tap_raft_message(direction: Direction, m: &RaftMessage) -> TappedMessage {
+    TappedMessage {
+        direction: direction,
+        region_id: m.get_region_id(),
+        from_store_id: m.get_from_peer().get_store_id(),
+        to_store_id: m.get_to_peer().get_store_id(),
+        msg_type: m.get_message().get_msg_type(),
+        dropped: false,
+        held: false,
     }
+}
 
-    pub fn is_send(&self) -> bool {
+impl Tap for RaftMessage {
+    fn tapped(&self) -> Option<TappedMessage> {
+        Some(tap_raft_message(Direction::Send, self))
+    }
+}
+
+impl Tap for StoreMsg {
+    fn tapped(&self) -> Option<TappedMessage> {
         match *self {
-            Direction::Send | Direction::Both => true,
-            Direction::Recv => false,
+            StoreMsg::RaftMessage(ref m) => Some(tap_raft_message(Direction::Recv, m)),
+            _ => None,
         }
     }
 }
 
-/// Drop specified messages for the store with special region.
-///
-/// If `msg_type` is None, all message will be filtered.
-#[derive(Clone)]
-pub struct RegionPacketFilter {
-    region_id: u64,
-    store_id: u64,
-    direction: Direction,
-    block: Either<Arc<AtomicUsize>, Arc<AtomicBool>>,
-    msg_type: Option<MessageType>,
+/// What the filters of a `SimulateTransport` did to a message. Filters drop
+/// messages by returning an error, see `check_messages`; a filter leaving no
+/// message to send without an error holds them back to send them later.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Passed,
+    Dropped,
+    Delayed,
+    Duplicated,
+    Held,
 }
 
-impl Filter<RaftMessage> for RegionPacketFilter {
+/// The outcome of sending a message, see `SimulateTransport::send_inspect`.
+#[derive(Debug)]
+pub enum SendOutcome {
+    Dropped,
+    Delivered,
+    Held,
+    Failed(Error),
+}
+
+/// A message recorded by a `SimulateTransport`, see `SimulateTransport::record`.
+/// The delay includes the time the filters blocked the sender, `blocked`.
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub msg: TappedMessage,
+    pub decision: Decision,
+    pub delay: Option<time::Duration>,
+    pub blocked: time::Duration,
+}
+
+/// Keeps the latest `capacity` trace entries.
+struct Recorder {
+    capacity: usize,
+    trace: VecDeque<TraceEntry>,
+}
+
+/// Counts of the faults injected by the filters of a `SimulateTransport`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransportStats {
+    pub dropped: usize,
+    pub delayed: usize,
+    pub delay: time::Duration,
+    pub delay_p50: time::Duration,
+    pub delay_p99: time::Duration,
+    pub duplicated: usize,
+}
+
+#[derive(Default)]
+struct StatsCounters {
+    dropped: AtomicUsize,
+    delayed: AtomicUsize,
+    delay_ms: AtomicUsize,
+    duplicated: AtomicUsize,
+    delays: Mutex<DelaySamples>,
+}
+
+/// The most delays kept to estimate the percentiles of.
+const MAX_DELAY_SAMPLES: usize = 4096;
+
+/// A uniform sample of the delays in milliseconds. It uses reservoir sampling,
+/// so the memory stays bounded however many messages are delayed.
+#[derive(Default)]
+struct DelaySamples {
+    seen: usize,
+    samples: Vec<u64>,
+}
+
+impl DelaySamples {
+    fn add(&mut self, delay_ms: u64) {
+        self.seen += 1;
+        if self.samples.len() < MAX_DELAY_SAMPLES {
+            self.samples.push(delay_ms);
+            return;
+        }
+        let i = rand::thread_rng().gen_range(0, self.seen);
+        if i < MAX_DELAY_SAMPLES {
+            self.samples[i] = delay_ms;
+        }
+    }
+}
+
+/// The `p`th percentile of the sorted samples, zero if there are none.
+fn percentile(sorted: &[u64], p: usize) -> time::Duration {
+    if sorted.is_empty() {
+        return time::Duration::from_millis(0);
+    }
+    time::Duration::from_millis(sorted[(sorted.len() - 1) * p / 100])
+}
+
+/// The filters of a transport. Messages still held back by them are delivered
+/// when the last transport sharing them is dropped, rather than silently lost.
+struct FilterChain<M, C: Channel<M>> {
+    filters: Vec<Box<Filter<M>>>,
+    ch: Arc<Mutex<C>>,
+}
+
+impl<M, C: Channel<M>> Drop for FilterChain<M, C> {
+    fn drop(&mut self) {
+        let ch = self.ch.lock().unwrap();
+        for msg in self.filters.iter().flat_map(|f| f.drain()) {
+            if let Err(e) = ch.send(msg) {
+                debug!("failed to deliver drained message: {:?}", e);
+            }
+        }
+    }
+}
+
+pub struct SimulateTransport<M, C: Channel<M>> {
+    filters: Arc<RwLock<FilterChain<M, C>>>,
+    ch: Arc<Mutex<C>>,
+    delayer: Arc<Mutex<Option<Delayer<M>>>>,
+    taps: Arc<Mutex<Vec<Sender<TappedMessage>>>>,
+    recorder: Arc<Mutex<Option<Recorder>>>,
+    stats: Arc<StatsCounters>,
+}
+
+impl<M: Tap + Send + 'static, C: Channel<M> + 'static> SimulateTransport<M, C> {
+    pub fn new(ch: C) -> SimulateTransport<M, C> {
+        let ch = Arc::new(Mutex::new(ch));
+        SimulateTransport {
+            filters: Arc::new(RwLock::new(FilterChain {
+                filters: vec![],
+                ch: ch.clone(),
+            })),
+            ch: ch,
+            delayer: Arc::new(Mutex::new(None)),
+            taps: Arc::new(Mutex::new(vec![])),
+            recorder: Arc::new(Mutex::new(None)),
+            stats: Arc::new(StatsCounters::default()),
+        }
+    }
+
+    /// Messages dropped or duplicated by the filters, and those delayed along
+    /// with the distribution of their delays. A filter returning fewer messages
+    /// than it's given drops the rest, unless it returns none without an error,
+    /// which holds them back. A message is delayed for as long
+    /// as the filters block the sender in `before`, see `block_sender`, plus
+    /// the delay given by `Filter::delay`. The percentiles are estimated from
+    /// a sample of at most `MAX_DELAY_SAMPLES` delays.
+    pub fn stats(&self) -> TransportStats {
+        let delay_ms = self.stats.delay_ms.load(Ordering::SeqCst) as u64;
+        let mut delays = self.stats.delays.lock().unwrap().samples.clone();
+        delays.sort();
+        TransportStats {
+            dropped: self.stats.dropped.load(Ordering::SeqCst),
+            delayed: self.stats.delayed.load(Ordering::SeqCst),
+            delay: time::Duration::from_millis(delay_ms),
+            delay_p50: percentile(&delays, 50),
+            delay_p99: percentile(&delays, 99),
+            duplicated: self.stats.duplicated.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.dropped.store(0, Ordering::SeqCst);
+        self.stats.delayed.store(0, Ordering::SeqCst);
+        self.stats.delay_ms.store(0, Ordering::SeqCst);
+        self.stats.duplicated.store(0, Ordering::SeqCst);
+        *self.stats.delays.lock().unwrap() = DelaySamples::default();
+    }
+
+    /// Stream a copy of every raft message handed to the transport and
+    /// whether it's dropped or held back by the filters, without affecting
+    /// the delivery.
+    pub fn tap(&self) -> Receiver<TappedMessage> {
+        let (tx, rx) = mpsc::channel();
+        self.taps.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Start recording the raft messages handed to the transport and what the
+    /// filters did to them, keeping the latest `capacity` ones. Recording is
+    /// off until this is called, restarting it discards the trace.
+    pub fn record(&self, capacity: usize) {
+        *self.recorder.lock().unwrap() = Some(Recorder {
+            capacity: capacity,
+            trace: VecDeque::with_capacity(capacity),
+        });
+    }
+
+    /// The recorded messages in the order they were sent.
+    pub fn trace(&self) -> Vec<TraceEntry> {
+        match *self.recorder.lock().unwrap() {
+            Some(ref recorder) => recorder.trace.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    fn record_decision(
+        &self,
+        msg: TappedMessage,
+        decision: Decision,
+        delay: Option<time::Duration>,
+        blocked: time::Duration,
+    ) {
+        if let Some(ref mut recorder) = *self.recorder.lock().unwrap() {
+            if recorder.capacity == 0 {
+                return;
+            }
+            if recorder.trace.len() == recorder.capacity {
+                recorder.trace.pop_front();
+            }
+            recorder.trace.push_back(TraceEntry {
+                msg: msg,
+                decision: decision,
+                delay: delay,
+                blocked: blocked,
+            });
+        }
+    }
+
+    fn record_delay(&self, delay: time::Duration) {
+        let delay_ms = duration_to_ms(delay);
+        self.stats.delayed.fetch_add(1, Ordering::SeqCst);
+        self.stats
+            .delay_ms
+            .fetch_add(delay_ms as usize, Ordering::SeqCst);
+        self.stats.delays.lock().unwrap().add(delay_ms);
+    }
+
+    /// Send the message like `Channel::send`, but tell whether it's dropped by
+    /// the filters, delivered, held back by them, or failed to be sent.
+    pub fn send_inspect(&self, msg: M) -> SendOutcome {
+        match self.send_filtered(msg) {
+            (Decision::Dropped, _) => SendOutcome::Dropped,
+            (_, Err(e)) => SendOutcome::Failed(e),
+            (Decision::Held, Ok(())) => SendOutcome::Held,
+            (_, Ok(())) => SendOutcome::Delivered,
+        }
+    }
+
+    /// Send the message through the filters, returns what they did to it along
+    /// with the result of sending it.
+    fn send_filtered(&self, msg: M) -> (Decision, Result<()>) {
+        let recording = self.recorder.lock().unwrap().is_some();
+        let mut tapped = if !recording && self.taps.lock().unwrap().is_empty() {
+            None
+        } else {
+            msg.tapped()
+        };
+        let mut applied = vec![];
+        let mut msgs = vec![msg];
+        let chain = self.filters.rl();
+        let filters = &chain.filters;
+        let mut res = Ok(());
+        let zero = time::Duration::from_millis(0);
+        let outer = BLOCKED.with(|b| b.get());
+        BLOCKED.with(|b| b.set(zero));
+        let mut dropped_msgs = 0;
+        for filter in filters.iter() {
+            let count = msgs.len();
+            let (r, a) = filter.before_scoped(&mut msgs);
+            res = r;
+            applied.push(a);
+            if res.is_err() {
+                dropped_msgs += count;
+                break;
+            }
+            // Returning some of the messages drops the rest, while returning
+            // none of them without an error holds them back.
+            if !msgs.is_empty() && msgs.len() < count {
+                dropped_msgs += count - msgs.len();
+            }
+        }
+        let blocked = BLOCKED.with(|b| b.get());
+        BLOCKED.with(|b| b.set(outer + blocked));
+        let dropped = res.is_err();
+        if dropped_msgs > 0 {
+            self.stats.dropped.fetch_add(dropped_msgs, Ordering::SeqCst);
+        }
+        if !dropped && msgs.len() > 1 {
+            self.stats
+                .duplicated
+                .fetch_add(msgs.len() - 1, Ordering::SeqCst);
+        }
+        let held = !dropped && msgs.is_empty();
+        let mut decision = if dropped {
+            Decision::Dropped
+        } else if held {
+            Decision::Held
+        } else if msgs.len() > 1 {
+            Decision::Duplicated
+        } else {
+            Decision::Passed
+        };
+        if let Some(ref mut tapped) = tapped {
+            tapped.dropped = dropped;
+            tapped.held = held;
+            let mut taps = self.taps.lock().unwrap();
+            taps.retain(|tap| tap.send(tapped.clone()).is_ok());
+        }
+        let mut max_delay = None;
+        if res.is_ok() {
+            for msg in msgs {
+                let delay = filters.iter().filter_map(|f| f.delay(&msg)).max();
+                if delay.is_some() || blocked > zero {
+                    let total = blocked + delay.unwrap_or(zero);
+                    self.record_delay(total);
+                    if decision == Decision::Passed {
+                        decision = Decision::Delayed;
+                    }
+                    max_delay = cmp::max(max_delay, Some(total));
+                }
+                res = match delay {
+                    Some(delay) => self.send_delayed(delay, msg),
+                    None => self.ch.lock().unwrap().send(msg),
+                };
+                if res.is_err() {
+                    break;
+                }
+            }
+        }
+        if recording {
+            if let Some(tapped) = tapped {
+                self.record_decision(tapped, decision, max_delay, blocked);
+            }
+        }
+        for (filter, &a) in filters.iter().zip(&applied).rev() {
+            res = filter.after_scoped(a, res);
+        }
+        (decision, res)
+    }
+
+    /// Hand the message to the background thread, which is started on the
+    /// first delayed message.
+    ///
+    /// Delayed messages to the same store are delivered in the order they are
+    /// sent: a message is never due before an earlier one to the same store,
+    /// even if it's given a shorter delay. Reordering only happens where a
+    /// filter asks for it, like `OutOrderFilter` shuffling in `before`, which
+    /// runs before the messages are handed here.
+    fn send_delayed(&self, delay: time::Duration, msg: M) -> Result<()> {
+        let mut delayer = self.delayer.lock().unwrap();
+        if delayer.is_none() {
+            let (tx, rx) = mpsc::channel();
+            let ch = self.ch.clone();
+            thread::Builder::new()
+                .name(thd_name!("simulate-delayer"))
+                .spawn(move || deliver_delayed(rx, ch))
+                .unwrap();
+            *delayer = Some(Delayer {
+                tx: tx,
+                last_due: HashMap::new(),
+            });
+        }
+        let delayer = delayer.as_mut().unwrap();
+        let to_store_id = msg.tapped().map_or(0, |t| t.to_store_id);
+        let mut at = time::Instant::now() + delay;
+        if let Some(&last) = delayer.last_due.get(&to_store_id) {
+            if last > at {
+                at = last;
+            }
+        }
+        delayer.last_due.insert(to_store_id, at);
+        delayer
+            .tx
+            .send((at, msg))
+            .map_err(|_| box_err!("failed to delay message"))
+    }
+
+    /// Remove all filters. Messages still held back by them are delivered
+    /// rather than silently lost.
+    pub fn clear_filters(&mut self) {
+        self.set_filters(vec![]);
+    }
+
+    /// Replace all filters at once, a concurrent `send` sees either the old
+    /// filters or the new ones. Messages held back by the old filters are
+    /// delivered like `clear_filters`.
+    pub fn set_filters(&mut self, filters: Vec<Box<Filter<M>>>) {
+        let old = {
+            let mut current = self.filters.wl();
+            mem::replace(&mut current.filters, filters)
+        };
+        let ch = self.ch.lock().unwrap();
+        for msg in old.iter().flat_map(|f| f.drain()) {
+            if let Err(e) = ch.send(msg) {
+                debug!("failed to deliver drained message: {:?}", e);
+            }
+        }
+    }
+
+    pub fn add_filter(&mut self, filter: Box<Filter<M>>) {
+        self.filters.wl().filters.push(filter);
+    }
+
+    /// Deliver the messages held back by all filters in order, so that nothing
+    /// is left stuck in a filter when a test finishes.
+    pub fn flush_filters(&self) -> Result<()> {
+        let msgs: Vec<_> = self.filters
+            .rl()
+            .filters
+            .iter()
+            .flat_map(|f| f.drain())
+            .collect();
+        let ch = self.ch.lock().unwrap();
+        for msg in msgs {
+            ch.send(msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl<M: Tap + Send + 'static, C: Channel<M> + 'static> Channel<M> for SimulateTransport<M, C> {
+    fn send(&self, msg: M) -> Result<()> {
+        self.send_filtered(msg).1
+    }
+}
+
+impl<M, C: Channel<M>> Clone for SimulateTransport<M, C> {
+    fn clone(&self) -> SimulateTransport<M, C> {
+        SimulateTransport {
+            filters: self.filters.clone(),
+            ch: self.ch.clone(),
+            delayer: self.delayer.clone(),
+            taps: self.taps.clone(),
+            recorder: self.recorder.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<C: Channel<RaftMessage> + 'static> Transport for SimulateTransport<RaftMessage, C> {
+    fn send(&self, m: RaftMessage) -> Result<()> {
+        Channel::send(self, m)
+    }
+
+    fn flush(&mut self) {
+        self.ch.lock().unwrap().flush();
+    }
+}
+
+impl<C: Channel<StoreMsg> + 'static> RaftStoreRouter for SimulateTransport<StoreMsg, C> {
+    fn send(&self, m: StoreMsg) -> Result<()> {
+        Channel::send(self, m)
+    }
+
+    fn try_send(&self, m: StoreMsg) -> Result<()> {
+        Channel::send(self, m)
+    }
+
+    fn significant_send(&self, m: SignificantMsg) -> Result<()> {
+        self.ch.lock().unwrap().significant_send(m)
+    }
+}
+
+pub trait FilterFactory {
+    fn generate(&self, node_id: u64) -> Vec<SendFilter>;
+}
+
+#[derive(Default)]
+pub struct DefaultFilterFactory<F: Filter<RaftMessage> + Default>(PhantomData<F>);
+
+impl<F: Filter<RaftMessage> + Default + 'static> FilterFactory for DefaultFilterFactory<F> {
+    fn generate(&self, _: u64) -> Vec<SendFilter> {
+        vec![Box::new(F::default())]
+    }
+}
+
+pub struct CloneFilterFactory<F: Filter<RaftMessage> + Clone>(pub F);
+
+/// Installs a clone of the filter on every node. Seeded filters are reseeded
+/// with the node id, see `Filter::reseed`.
+impl<F: Filter<RaftMessage> + Clone + 'static> FilterFactory for CloneFilterFactory<F> {
+    fn generate(&self, node_id: u64) -> Vec<SendFilter> {
+        let mut filter = self.0.clone();
+        filter.reseed(node_id);
+        vec![Box::new(filter)]
+    }
+}
+
+struct PartitionFilter {
+    node_ids: Vec<u64>,
+}
+
+impl Filter<RaftMessage> for PartitionFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| !self.node_ids.contains(&m.get_to_peer().get_store_id()));
+        check_messages(msgs)
+    }
+}
+
+/// `NetworkPartitionFilter` splits the cluster into the given group of stores and
+/// everyone else. Messages crossing the boundary are dropped, while messages
+/// within either side pass through.
+#[derive(Clone)]
+pub struct NetworkPartitionFilter {
+    group: HashSet<u64>,
+}
+
+impl NetworkPartitionFilter {
+    pub fn new(group: Vec<u64>) -> NetworkPartitionFilter {
+        NetworkPartitionFilter {
+            group: group.into_iter().collect(),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for NetworkPartitionFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            self.group.contains(&m.get_from_peer().get_store_id()) ==
+                self.group.contains(&m.get_to_peer().get_store_id())
+        });
+        check_messages(msgs)
+    }
+}
+
+pub struct PartitionFilterFactory {
+    s1: Vec<u64>,
+    s2: Vec<u64>,
+}
+
+impl PartitionFilterFactory {
+    pub fn new(s1: Vec<u64>, s2: Vec<u64>) -> PartitionFilterFactory {
+        PartitionFilterFactory { s1: s1, s2: s2 }
+    }
+}
+
+impl FilterFactory for PartitionFilterFactory {
+    fn generate(&self, node_id: u64) -> Vec<SendFilter> {
+        if self.s1.contains(&node_id) {
+            return vec![
+                box PartitionFilter {
+                    node_ids: self.s2.clone(),
+                },
+            ];
+        }
+        return vec![
+            box PartitionFilter {
+                node_ids: self.s1.clone(),
+            },
+        ];
+    }
+}
+
+pub struct IsolationFilterFactory {
+    node_id: u64,
+}
+
+impl IsolationFilterFactory {
+    pub fn new(node_id: u64) -> IsolationFilterFactory {
+        IsolationFilterFactory { node_id: node_id }
+    }
+}
+
+impl FilterFactory for IsolationFilterFactory {
+    fn generate(&self, node_id: u64) -> Vec<SendFilter> {
+        if node_id == self.node_id {
+            return vec![box DropPacketFilter::new(100)];
+        }
+        vec![
+            box PartitionFilter {
+                node_ids: vec![self.node_id],
+            },
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Recv,
+    Send,
+    Both,
+}
+
+impl Direction {
+    pub fn is_recv(&self) -> bool {
+        match *self {
+            Direction::Recv | Direction::Both => true,
+            Direction::Send => false,
+        }
+    }
+
+    pub fn is_send(&self) -> bool {
+        match *self {
+            Direction::Send | Direction::Both => true,
+            Direction::Recv => false,
+        }
+    }
+}
+
+/// Drop specified messages for the store with special region.
+///
+/// If `msg_type` is None, all message will be filtered.
+#[derive(Clone)]
+pub struct RegionPacketFilter {
+    region_id: u64,
+    store_id: u64,
+    direction: Direction,
+    block: Either<Arc<AtomicUsize>, Arc<AtomicBool>>,
+    msg_type: Option<MessageType>,
+}
+
+impl Filter<RaftMessage> for RegionPacketFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            let region_id = m.get_region_id();
+            let from_store_id = m.get_from_peer().get_store_id();
+            let to_store_id = m.get_to_peer().get_store_id();
+
+            if self.region_id == region_id &&
+                (self.direction.is_send() && self.store_id == from_store_id ||
+                    self.direction.is_recv() && self.store_id == to_store_id) &&
+                self.msg_type
+                    .as_ref()
+                    .map_or(true, |t| t == &m.get_message().get_msg_type())
+            {
+                return match self.block {
+                    Either::Left(ref count) => loop {
+                        let left = count.load(Ordering::SeqCst);
+                        if left == 0 {
+                            return false;
+                        }
+                        if count.compare_and_swap(left, left - 1, Ordering::SeqCst) == left {
+                            return true;
+                        }
+                    },
+                    Either::Right(ref block) => !block.load(Ordering::SeqCst),
+                };
+            }
+            true
+        });
+        check_messages(msgs)
+    }
+}
+
+impl RegionPacketFilter {
+    pub fn new(region_id: u64, store_id: u64) -> RegionPacketFilter {
+        RegionPacketFilter {
+            region_id: region_id,
+            store_id: store_id,
+            direction: Direction::Both,
+            msg_type: None,
+            block: Either::Right(Arc::new(AtomicBool::new(true))),
+        }
+    }
+
+    pub fn direction(mut self, direction: Direction) -> RegionPacketFilter {
+        self.direction = direction;
+        self
+    }
+
+    pub fn msg_type(mut self, m_type: MessageType) -> RegionPacketFilter {
+        self.msg_type = Some(m_type);
+        self
+    }
+
+    pub fn allow(mut self, number: usize) -> RegionPacketFilter {
+        self.block = Either::Left(Arc::new(AtomicUsize::new(number)));
+        self
+    }
+
+    pub fn when(mut self, condition: Arc<AtomicBool>) -> RegionPacketFilter {
+        self.block = Either::Right(condition);
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct SnapshotFilter {
+    drop: AtomicBool,
+}
+
+impl Filter<RaftMessage> for SnapshotFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            m.get_message().get_msg_type() != MessageType::MsgSnapshot
+        });
+        self.drop.store(msgs.is_empty(), Ordering::Relaxed);
+        check_messages(msgs)
+    }
+
+    fn after(&self, x: Result<()>) -> Result<()> {
+        if self.drop.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            x
+        }
+    }
+}
+
+/// `CollectSnapshotFilter` is a simulation transport filter to simulate the simultaneous delivery
+/// of multiple snapshots from different peers. It collects the snapshots from different
+/// peers and drop the subsequent snapshots from the same peers. Currently, if there are
+/// more than 1 snapshots in this filter, all the snapshots will be dilivered at once.
+pub struct CollectSnapshotFilter {
+    dropped: AtomicBool,
+    stale: AtomicBool,
+    pending_msg: Mutex<HashMap<u64, StoreMsg>>,
+    pending_count_sender: Mutex<Sender<usize>>,
+}
+
+impl CollectSnapshotFilter {
+    pub fn new(sender: Sender<usize>) -> CollectSnapshotFilter {
+        CollectSnapshotFilter {
+            dropped: AtomicBool::new(false),
+            stale: AtomicBool::new(false),
+            pending_msg: Mutex::new(HashMap::new()),
+            pending_count_sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl Filter<StoreMsg> for CollectSnapshotFilter {
+    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
+        if self.stale.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut to_send = vec![];
+        let mut pending_msg = self.pending_msg.lock().unwrap();
+        for m in msgs.drain(..) {
+            let (is_pending, from_peer_id) = match m {
+                StoreMsg::RaftMessage(ref msg) => {
+                    if msg.get_message().get_msg_type() == MessageType::MsgSnapshot {
+                        let from_peer_id = msg.get_from_peer().get_id();
+                        if pending_msg.contains_key(&from_peer_id) {
+                            // Drop this snapshot message directly since it's from a seen peer
+                            continue;
+                        } else {
+                            // Pile the snapshot from unseen peer
+                            (true, from_peer_id)
+                        }
+                    } else {
+                        (false, 0)
+                    }
+                }
+                _ => (false, 0),
+            };
+            if is_pending {
+                self.dropped
+                    .compare_and_swap(false, true, Ordering::Relaxed);
+                pending_msg.insert(from_peer_id, m);
+                let sender = self.pending_count_sender.lock().unwrap();
+                sender.send(pending_msg.len()).unwrap();
+            } else {
+                to_send.push(m);
+            }
+        }
+        // Deliver those pending snapshots if there are more than 1.
+        if pending_msg.len() > 1 {
+            self.dropped
+                .compare_and_swap(true, false, Ordering::Relaxed);
+            msgs.extend(pending_msg.drain().map(|(_, v)| v));
+            self.stale.compare_and_swap(false, true, Ordering::Relaxed);
+        }
+        msgs.extend(to_send);
+        check_messages(msgs)
+    }
+
+    fn after(&self, res: Result<()>) -> Result<()> {
+        if res.is_err() && self.dropped.load(Ordering::Relaxed) {
+            self.dropped
+                .compare_and_swap(true, false, Ordering::Relaxed);
+            Ok(())
+        } else {
+            res
+        }
+    }
+}
+
+pub struct DropSnapshotFilter {
+    notifier: Mutex<Sender<u64>>,
+}
+
+impl DropSnapshotFilter {
+    pub fn new(ch: Sender<u64>) -> DropSnapshotFilter {
+        DropSnapshotFilter {
+            notifier: Mutex::new(ch),
+        }
+    }
+}
+
+impl Filter<StoreMsg> for DropSnapshotFilter {
+    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
+        let notifier = self.notifier.lock().unwrap();
+        msgs.retain(|m| match *m {
+            StoreMsg::RaftMessage(ref msg) => {
+                if msg.get_message().get_msg_type() != MessageType::MsgSnapshot {
+                    true
+                } else {
+                    let idx = msg.get_message().get_snapshot().get_metadata().get_index();
+                    if let Err(e) = notifier.send(idx) {
+                        error!("failed to notify snapshot {:?}: {:?}", msg, e);
+                    }
+                    false
+                }
+            }
+            _ => true,
+        });
+        Ok(())
+    }
+}
+
+/// Corrupt the data of the first `count` snapshots received, so that they
+/// can't be decoded and the sender has to send another one.
+pub struct CorruptSnapshotFilter {
+    remaining: AtomicUsize,
+    notifier: Mutex<Sender<u64>>,
+}
+
+impl CorruptSnapshotFilter {
+    pub fn new(count: usize, ch: Sender<u64>) -> CorruptSnapshotFilter {
+        CorruptSnapshotFilter {
+            remaining: AtomicUsize::new(count),
+            notifier: Mutex::new(ch),
+        }
+    }
+}
+
+impl Filter<StoreMsg> for CorruptSnapshotFilter {
+    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
+        for m in msgs.iter_mut() {
+            let msg = match *m {
+                StoreMsg::RaftMessage(ref mut msg) => msg.mut_message(),
+                _ => continue,
+            };
+            if msg.get_msg_type() != MessageType::MsgSnapshot {
+                continue;
+            }
+            let corrupt = loop {
+                let remaining = self.remaining.load(Ordering::SeqCst);
+                if remaining == 0 {
+                    break false;
+                }
+                if self.remaining
+                    .compare_and_swap(remaining, remaining - 1, Ordering::SeqCst) ==
+                    remaining
+                {
+                    break true;
+                }
+            };
+            if !corrupt {
+                continue;
+            }
+            // An unterminated varint never decodes.
+            msg.mut_snapshot().set_data(vec![0xff; 8]);
+            let idx = msg.get_snapshot().get_metadata().get_index();
+            if let Err(e) = self.notifier.lock().unwrap().send(idx) {
+                error!("failed to notify corrupted snapshot {}: {:?}", idx, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `SnapshotStreamFilter` lets the first `after_n` snapshots of each stream,
+/// identified by the region and the sending peer, through and drops the rest,
+/// so the sender has to restart sending the snapshot. A snapshot is carried
+/// by a single `MsgSnapshot` here, there are no chunk messages to count.
+pub struct SnapshotStreamFilter {
+    after_n: usize,
+    streams: Mutex<HashMap<(u64, u64), usize>>,
+    notifier: Mutex<Sender<u64>>,
+}
+
+impl SnapshotStreamFilter {
+    pub fn new(after_n: usize, ch: Sender<u64>) -> SnapshotStreamFilter {
+        SnapshotStreamFilter {
+            after_n: after_n,
+            streams: Mutex::new(HashMap::new()),
+            notifier: Mutex::new(ch),
+        }
+    }
+}
+
+impl Filter<StoreMsg> for SnapshotStreamFilter {
+    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
+        let mut streams = self.streams.lock().unwrap();
+        let notifier = self.notifier.lock().unwrap();
+        msgs.retain(|m| {
+            let msg = match *m {
+                StoreMsg::RaftMessage(ref msg) => msg,
+                _ => return true,
+            };
+            if msg.get_message().get_msg_type() != MessageType::MsgSnapshot {
+                return true;
+            }
+            let stream = (msg.get_region_id(), msg.get_from_peer().get_id());
+            let seen = streams.entry(stream).or_insert(0);
+            *seen += 1;
+            if *seen <= self.after_n {
+                return true;
+            }
+            let idx = msg.get_message().get_snapshot().get_metadata().get_index();
+            if let Err(e) = notifier.send(idx) {
+                error!("failed to notify dropped snapshot {}: {:?}", idx, e);
+            }
+            false
+        });
+        Ok(())
+    }
+}
+
+/// Filter leading duplicated Snap.
+///
+/// It will pause the first snapshot and fiter out all the snapshot that
+/// are same as first snapshot msg until the first different snapshot shows up.
+pub struct LeadingDuplicatedSnapshotFilter {
+    dropped: AtomicBool,
+    stale: Arc<AtomicBool>,
+    last_msg: Mutex<Option<RaftMessage>>,
+}
+
+impl LeadingDuplicatedSnapshotFilter {
+    pub fn new(stale: Arc<AtomicBool>) -> LeadingDuplicatedSnapshotFilter {
+        LeadingDuplicatedSnapshotFilter {
+            dropped: AtomicBool::new(false),
+            stale: stale,
+            last_msg: Mutex::new(None),
+        }
+    }
+}
+
+impl Filter<StoreMsg> for LeadingDuplicatedSnapshotFilter {
+    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
+        let mut last_msg = self.last_msg.lock().unwrap();
+        let mut stale = self.stale.load(Ordering::Relaxed);
+        if stale {
+            if last_msg.is_some() {
+                msgs.push(StoreMsg::RaftMessage(last_msg.take().unwrap()));
+            }
+            return check_messages(msgs);
+        }
+        let mut to_send = vec![];
+        for m in msgs.drain(..) {
+            match m {
+                StoreMsg::RaftMessage(msg) => {
+                    if msg.get_message().get_msg_type() == MessageType::MsgSnapshot && !stale {
+                        if last_msg.as_ref().map_or(false, |l| l != &msg) {
+                            to_send.push(StoreMsg::RaftMessage(last_msg.take().unwrap()));
+                            *last_msg = Some(msg);
+                            stale = true;
+                        } else {
+                            self.dropped.store(true, Ordering::Relaxed);
+                            *last_msg = Some(msg);
+                        }
+                    } else {
+                        to_send.push(StoreMsg::RaftMessage(msg));
+                    }
+                }
+                _ => {
+                    to_send.push(m);
+                }
+            }
+        }
+        self.stale.store(stale, Ordering::Relaxed);
+        msgs.extend(to_send);
+        check_messages(msgs)
+    }
+
+    fn after(&self, res: Result<()>) -> Result<()> {
+        let dropped = self.dropped
+            .compare_and_swap(true, false, Ordering::Relaxed);
+        if res.is_err() && dropped {
+            Ok(())
+        } else {
+            res
+        }
+    }
+}
+
+/// `RandomLatencyFilter` is a transport filter to simulate randomized network latency.
+/// Based on a randomized rate, `RandomLatencyFilter` will decide whether to delay
+/// the sending of any message. It's could be used to simulate the message sending
+/// in a network with random latency, where messages could be delayed, disordered or lost.
+pub struct RandomLatencyFilter {
+    delay_rate: u32,
+    delayed_msgs: Mutex<Vec<RaftMessage>>,
+    rng: FilterRng,
+}
+
+impl RandomLatencyFilter {
+    pub fn new(rate: u32) -> RandomLatencyFilter {
+        RandomLatencyFilter {
+            delay_rate: rate,
+            delayed_msgs: Mutex::new(vec![]),
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> RandomLatencyFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+
+    fn will_delay(&self, _: &RaftMessage) -> bool {
+        self.rng.next_u32() % 100u32 >= self.delay_rate
+    }
+}
+
+impl Filter<RaftMessage> for RandomLatencyFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut to_send = vec![];
+        let mut to_delay = vec![];
+        let mut delayed_msgs = self.delayed_msgs.lock().unwrap();
+        // check whether to send those messages which are delayed previouly
+        // and check whether to send any newly incoming message if they are not delayed
+        for m in delayed_msgs.drain(..).chain(msgs.drain(..)) {
+            if self.will_delay(&m) {
+                to_delay.push(m);
+            } else {
+                to_send.push(m);
+            }
+        }
+        delayed_msgs.extend(to_delay);
+        msgs.extend(to_send);
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.delayed_msgs.lock().unwrap().drain(..).collect()
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
+}
+
+impl Clone for RandomLatencyFilter {
+    fn clone(&self) -> RandomLatencyFilter {
+        let delayed_msgs = self.delayed_msgs.lock().unwrap();
+        RandomLatencyFilter {
+            delay_rate: self.delay_rate,
+            delayed_msgs: Mutex::new(delayed_msgs.clone()),
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+/// `RecvBufferLimitFilter` simulates receivers whose inbound buffers overflow.
+///
+/// Every message is held for `hold` before it's delivered, like a receiver
+/// slow to take messages off its buffer. Once more than `limit` messages are
+/// pending for a store, the oldest one is dropped and counted as overflowed.
+/// Due messages are sent ahead of the next message going through the filter.
+/// Clones share the same buffers, so it can be installed on every node with
+/// `CloneFilterFactory`.
+#[derive(Clone)]
+pub struct RecvBufferLimitFilter {
+    limit: usize,
+    hold: time::Duration,
+    buffered: Arc<Mutex<HashMap<u64, VecDeque<(time::Instant, RaftMessage)>>>>,
+    overflowed: Arc<AtomicUsize>,
+}
+
+impl RecvBufferLimitFilter {
+    pub fn new(limit: usize, hold: time::Duration) -> RecvBufferLimitFilter {
+        RecvBufferLimitFilter {
+            limit: limit,
+            hold: hold,
+            buffered: Arc::new(Mutex::new(HashMap::new())),
+            overflowed: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The number of messages pending for the store.
+    pub fn pending(&self, store_id: u64) -> usize {
+        let buffered = self.buffered.lock().unwrap();
+        buffered.get(&store_id).map_or(0, |q| q.len())
+    }
+
+    /// The number of messages dropped because the buffers overflowed.
+    pub fn overflowed(&self) -> usize {
+        self.overflowed.load(Ordering::SeqCst)
+    }
+}
+
+impl Filter<RaftMessage> for RecvBufferLimitFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let now = time::Instant::now();
+        let mut buffered = self.buffered.lock().unwrap();
+        for m in msgs.drain(..) {
+            let queue = buffered
+                .entry(m.get_to_peer().get_store_id())
+                .or_insert_with(VecDeque::new);
+            queue.push_back((now + self.hold, m));
+            if queue.len() > self.limit {
+                queue.pop_front();
+                self.overflowed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        for queue in buffered.values_mut() {
+            while queue.front().map_or(false, |&(due, _)| due <= now) {
+                msgs.push(queue.pop_front().unwrap().1);
+            }
+        }
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        let mut buffered = self.buffered.lock().unwrap();
+        buffered
+            .drain()
+            .flat_map(|(_, q)| q.into_iter().map(|(_, m)| m))
+            .collect()
+    }
+}
+
+const MAX_REPLAY_CANDIDATES: usize = 1024;
+
+/// `ReplayCommittedFilter` re-sends `MsgAppend`s whose entries have been
+/// committed. Outgoing appends are buffered by the index of their last entry,
+/// and become replay candidates once a later message of the region carries a
+/// commit index covering them. Based on the given rate, one of the candidates
+/// is sent again along with the outgoing messages, which exercises whether
+/// applying committed entries is idempotent. Clones share the replay count.
+pub struct ReplayCommittedFilter {
+    rate: u32,
+    // Appends not known to be committed yet, by region and then by the index
+    // of their last entry and the peer they are sent to.
+    uncommitted: Mutex<HashMap<u64, BTreeMap<(u64, u64), RaftMessage>>>,
+    committed: Mutex<VecDeque<RaftMessage>>,
+    replayed: Arc<AtomicUsize>,
+    rng: FilterRng,
+}
+
+impl ReplayCommittedFilter {
+    pub fn new(rate: u32) -> ReplayCommittedFilter {
+        ReplayCommittedFilter {
+            rate: rate,
+            uncommitted: Mutex::new(HashMap::new()),
+            committed: Mutex::new(VecDeque::new()),
+            replayed: Arc::new(AtomicUsize::new(0)),
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> ReplayCommittedFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+
+    /// The number of appends replayed so far.
+    pub fn replayed(&self) -> usize {
+        self.replayed.load(Ordering::SeqCst)
+    }
+}
+
+impl Filter<RaftMessage> for ReplayCommittedFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut uncommitted = self.uncommitted.lock().unwrap();
+        let mut committed = self.committed.lock().unwrap();
+        for m in msgs.iter() {
+            let msg = m.get_message();
+            let appends = uncommitted
+                .entry(m.get_region_id())
+                .or_insert_with(BTreeMap::new);
+            let pending = appends.split_off(&(msg.get_commit() + 1, 0));
+            committed.extend(mem::replace(appends, pending).into_iter().map(|(_, a)| a));
+            while committed.len() > MAX_REPLAY_CANDIDATES {
+                committed.pop_front();
+            }
+            if msg.get_msg_type() != MessageType::MsgAppend {
+                continue;
+            }
+            if let Some(e) = msg.get_entries().last() {
+                if e.get_index() <= msg.get_commit() {
+                    committed.push_back(m.clone());
+                } else if appends.len() < MAX_REPLAY_CANDIDATES {
+                    appends.insert((e.get_index(), m.get_to_peer().get_id()), m.clone());
+                }
+            }
+        }
+        if !committed.is_empty() && self.rng.next_u32() % 100u32 < self.rate {
+            let idx = self.rng.next_u32() as usize % committed.len();
+            msgs.push(committed[idx].clone());
+            self.replayed.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
+}
+
+impl Clone for ReplayCommittedFilter {
+    fn clone(&self) -> ReplayCommittedFilter {
+        ReplayCommittedFilter {
+            replayed: self.replayed.clone(),
+            rng: self.rng.clone(),
+            ..ReplayCommittedFilter::new(self.rate)
+        }
+    }
+}
+
+/// `ConnectDelayFilter` simulates slow connection setup: only the first message
+/// to each destination store is delayed, later ones flow at normal speed.
+pub struct ConnectDelayFilter {
+    duration: time::Duration,
+    connected: Mutex<HashSet<u64>>,
+}
+
+impl ConnectDelayFilter {
+    pub fn new(duration: time::Duration) -> ConnectDelayFilter {
+        ConnectDelayFilter {
+            duration: duration,
+            connected: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for ConnectDelayFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut connected = self.connected.lock().unwrap();
+        let mut connecting = false;
+        for m in msgs.iter() {
+            connecting |= connected.insert(m.get_to_peer().get_store_id());
+        }
+        if connecting {
+            block_sender(self.duration);
+        }
+        Ok(())
+    }
+}
+
+impl Clone for ConnectDelayFilter {
+    fn clone(&self) -> ConnectDelayFilter {
+        ConnectDelayFilter::new(self.duration)
+    }
+}
+
+/// The lowest bandwidth a `BandwidthFilter` decays to in bytes per second.
+pub const MIN_BANDWIDTH: u64 = 1024;
+
+/// `BandwidthFilter` caps the throughput of a link, every message is delayed
+/// for as long as it takes to transfer its serialized size at the current
+/// bandwidth. With a half life, the bandwidth halves every `half_life` since
+/// the filter was created, which models a slowly failing link.
+///
+/// Messages share the link, so a message has to wait for the ones sent before
+/// it to get through first. The bandwidth never decays below `MIN_BANDWIDTH`,
+/// or the given bandwidth if it's lower, otherwise a message could be held
+/// back forever.
+pub struct BandwidthFilter {
+    bytes_per_sec: u64,
+    half_life: Option<time::Duration>,
+    created: time::Instant,
+    busy_until: Mutex<time::Instant>,
+}
+
+impl BandwidthFilter {
+    pub fn new(bytes_per_sec: u64) -> BandwidthFilter {
+        let now = time::Instant::now();
+        BandwidthFilter {
+            bytes_per_sec: bytes_per_sec,
+            half_life: None,
+            created: now,
+            busy_until: Mutex::new(now),
+        }
+    }
+
+    pub fn half_life(mut self, half_life: time::Duration) -> BandwidthFilter {
+        assert!(half_life > time::Duration::from_millis(0));
+        self.half_life = Some(half_life);
+        self
+    }
+
+    fn current_bandwidth(&self) -> f64 {
+        // Never divide by zero, a link without bandwidth still moves a byte.
+        let bandwidth = cmp::max(self.bytes_per_sec, 1) as f64;
+        match self.half_life {
+            None => bandwidth,
+            Some(half_life) => {
+                let halves = duration_to_sec(self.created.elapsed()) / duration_to_sec(half_life);
+                let floor = f64::min(bandwidth, MIN_BANDWIDTH as f64);
+                f64::max(bandwidth / 2f64.powf(halves), floor)
+            }
+        }
+    }
+}
+
+impl Filter<RaftMessage> for BandwidthFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let size: u64 = msgs.iter().map(|m| m.compute_size() as u64).sum();
+        let nanos = (size as f64 / self.current_bandwidth() * 1e9) as u64;
+        let transfer = time::Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32);
+        let done = {
+            let mut busy_until = self.busy_until.lock().unwrap();
+            let now = time::Instant::now();
+            let start = if *busy_until > now { *busy_until } else { now };
+            *busy_until = start + transfer;
+            *busy_until
+        };
+        let now = time::Instant::now();
+        if done > now {
+            block_sender(done - now);
+        }
+        Ok(())
+    }
+}
+
+impl Clone for BandwidthFilter {
+    fn clone(&self) -> BandwidthFilter {
+        BandwidthFilter {
+            bytes_per_sec: self.bytes_per_sec,
+            half_life: self.half_life,
+            created: self.created,
+            busy_until: Mutex::new(time::Instant::now()),
+        }
+    }
+}
+
+/// `TermMonotonicityFilter` checks that no peer ever sends a message with a
+/// term lower than one it has sent before, since the term of a raft node never
+/// goes backwards. Pre-vote messages carry a term the sender hasn't moved to
+/// yet, so they are ignored. Offending messages are recorded rather than
+/// dropped, and clones share the records so it can watch the whole cluster.
+#[derive(Clone, Default)]
+pub struct TermMonotonicityFilter {
+    max_terms: Arc<Mutex<HashMap<(u64, u64), u64>>>,
+    violations: Arc<Mutex<Vec<RaftMessage>>>,
+}
+
+impl TermMonotonicityFilter {
+    pub fn violations(&self) -> Vec<RaftMessage> {
+        self.violations.lock().unwrap().clone()
+    }
+}
+
+impl Filter<RaftMessage> for TermMonotonicityFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut max_terms = self.max_terms.lock().unwrap();
+        for m in msgs.iter() {
+            let msg = m.get_message();
+            match msg.get_msg_type() {
+                MessageType::MsgRequestPreVote | MessageType::MsgRequestPreVoteResponse => continue,
+                _ => {}
+            }
+            if msg.get_term() == 0 {
+                continue;
+            }
+            let key = (m.get_region_id(), m.get_from_peer().get_id());
+            let max_term = max_terms.entry(key).or_insert(0);
+            if msg.get_term() < *max_term {
+                self.violations.lock().unwrap().push(m.clone());
+            } else {
+                *max_term = msg.get_term();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `WaitForCommitFilter` holds all messages to the given store until `index` is
+/// known to be committed in the region, that is, until a message of the region
+/// carrying a commit index not less than `index` goes through any clone of the
+/// filter. It enforces a happens-before edge between the commit and whatever
+/// the held messages carry.
+pub struct WaitForCommitFilter {
+    region_id: u64,
+    index: u64,
+    store_id: u64,
+    committed: Arc<AtomicBool>,
+    held: Mutex<Vec<RaftMessage>>,
+}
+
+impl WaitForCommitFilter {
+    pub fn new(region_id: u64, index: u64, store_id: u64) -> WaitForCommitFilter {
+        WaitForCommitFilter {
+            region_id: region_id,
+            index: index,
+            store_id: store_id,
+            committed: Arc::new(AtomicBool::new(false)),
+            held: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn committed(&self) -> bool {
+        self.committed.load(Ordering::SeqCst)
+    }
+}
+
+impl Filter<RaftMessage> for WaitForCommitFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        if msgs.iter().any(|m| {
+            m.get_region_id() == self.region_id && m.get_message().get_commit() >= self.index
+        }) {
+            self.committed.store(true, Ordering::SeqCst);
+        }
+        let mut held = self.held.lock().unwrap();
+        if self.committed() {
+            let mut to_send: Vec<_> = held.drain(..).collect();
+            to_send.extend(msgs.drain(..));
+            msgs.extend(to_send);
+            return Ok(());
+        }
+        let (to_hold, to_send): (Vec<_>, Vec<_>) = msgs.drain(..)
+            .partition(|m| m.get_to_peer().get_store_id() == self.store_id);
+        held.extend(to_hold);
+        msgs.extend(to_send);
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.held.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Clone for WaitForCommitFilter {
+    fn clone(&self) -> WaitForCommitFilter {
+        WaitForCommitFilter {
+            region_id: self.region_id,
+            index: self.index,
+            store_id: self.store_id,
+            committed: self.committed.clone(),
+            held: Mutex::new(vec![]),
+        }
+    }
+}
+
+/// `PredicateFilter` consults the predicate for every message and drops it
+/// unless the predicate returns true. The predicate may capture anything that
+/// exposes cluster state, like the pd client, so a test can express conditions
+/// such as "drop all traffic to store 3 until the region is split".
+#[derive(Clone)]
+pub struct PredicateFilter {
+    predicate: Arc<Fn(&RaftMessage) -> bool + Send + Sync>,
+}
+
+impl PredicateFilter {
+    pub fn new<F>(predicate: F) -> PredicateFilter
+    where
+        F: Fn(&RaftMessage) -> bool + Send + Sync + 'static,
+    {
+        PredicateFilter {
+            predicate: Arc::new(predicate),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for PredicateFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| (self.predicate)(m));
+        check_messages(msgs)
+    }
+}
+
+/// `DropUntilFilter` drops every message until the condition is observed true,
+/// then passes everything from then on. The condition may capture cluster
+/// state, like the engine of the leader to tell whether a key is committed.
+/// A message checked right before the condition turns true is still dropped.
+#[derive(Clone)]
+pub struct DropUntilFilter {
+    condition: Arc<Fn() -> bool + Send + Sync>,
+    satisfied: Arc<AtomicBool>,
+}
+
+impl DropUntilFilter {
+    pub fn new<F>(condition: F) -> DropUntilFilter
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        DropUntilFilter {
+            condition: Arc::new(condition),
+            satisfied: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn satisfied(&self) -> bool {
+        self.satisfied.load(Ordering::SeqCst)
+    }
+}
+
+impl<M> Filter<M> for DropUntilFilter {
+    fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
+        if !self.satisfied() {
+            if !(self.condition)() {
+                msgs.clear();
+            } else {
+                self.satisfied.store(true, Ordering::SeqCst);
+            }
+        }
+        check_messages(msgs)
+    }
+}
+
+/// `PumpFilter` holds every message in a queue shared by its clones instead of
+/// sending it, so a test can pick the messages to deliver and their order, see
+/// `Cluster::hold_messages`. Removing it delivers the held messages.
+#[derive(Clone, Default)]
+pub struct PumpFilter {
+    pending: Arc<Mutex<VecDeque<RaftMessage>>>,
+}
+
+impl PumpFilter {
+    pub fn pending(&self) -> Vec<RaftMessage> {
+        self.pending.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Remove the first held message equal to `msg`, if any.
+    pub fn take(&self, msg: &RaftMessage) -> Option<RaftMessage> {
+        let mut pending = self.pending.lock().unwrap();
+        let pos = pending.iter().position(|m| m == msg);
+        pos.and_then(|pos| pending.remove(pos))
+    }
+
+    /// Remove the earliest held message, if any.
+    pub fn pop_front(&self) -> Option<RaftMessage> {
+        self.pending.lock().unwrap().pop_front()
+    }
+}
+
+impl Filter<RaftMessage> for PumpFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        self.pending.lock().unwrap().extend(msgs.drain(..));
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.pending.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// `DropOnly` marks the filters which only drop messages: they don't hold,
+/// delay, duplicate or change them, nor the result of sending them. Only these
+/// can be the leaves of a `FilterExpr`.
+pub trait DropOnly: Filter<RaftMessage> {}
+
+impl DropOnly for DropPacketFilter {}
+impl DropOnly for DropConfChangeFilter {}
+impl DropOnly for NetworkPartitionFilter {}
+impl DropOnly for RegionPacketFilter {}
+impl DropOnly for PredicateFilter {}
+impl DropOnly for DropUntilFilter {}
+impl DropOnly for DropIndexRangeFilter {}
+impl DropOnly for DropVotesInTermFilter {}
+impl DropOnly for DropFirstNFilter {}
+impl<F: DropOnly> DropOnly for ScopedFilter<F> {}
+impl DropOnly for FilterExpr {}
+
+/// `FilterExpr` combines the drop decisions of filters: `And` drops a message
+/// only if all its parts drop it, `Or` if any of them does. The filters at the
+/// top level of a transport behave like an `Or`. A leaf is given a copy of the
+/// message to tell whether it drops it, so it must only drop messages, see
+/// `DropOnly`. The parts are evaluated in order and short-circuit.
+pub enum FilterExpr {
+    Leaf(Box<DropOnly>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+impl FilterExpr {
+    fn drops(&self, m: &RaftMessage) -> bool {
+        match *self {
+            FilterExpr::Leaf(ref filter) => {
+                let mut msgs = vec![m.clone()];
+                filter.before(&mut msgs).is_err() || msgs.is_empty()
+            }
+            FilterExpr::And(ref exprs) => exprs.iter().all(|e| e.drops(m)),
+            FilterExpr::Or(ref exprs) => exprs.iter().any(|e| e.drops(m)),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for FilterExpr {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| !self.drops(m));
+        check_messages(msgs)
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        match *self {
+            FilterExpr::Leaf(ref mut filter) => filter.reseed(node_id),
+            FilterExpr::And(ref mut exprs) | FilterExpr::Or(ref mut exprs) => {
+                for e in exprs {
+                    e.reseed(node_id);
+                }
+            }
+        }
+    }
+}
+
+/// `DropIndexRangeFilter` drops the appends of the region carrying any entry
+/// with index in `[lo, hi]`.
+#[derive(Clone)]
+pub struct DropIndexRangeFilter {
+    region_id: u64,
+    lo: u64,
+    hi: u64,
+}
+
+impl DropIndexRangeFilter {
+    pub fn new(region_id: u64, lo: u64, hi: u64) -> DropIndexRangeFilter {
+        DropIndexRangeFilter {
+            region_id: region_id,
+            lo: lo,
+            hi: hi,
+        }
+    }
+}
+
+impl Filter<RaftMessage> for DropIndexRangeFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            let msg = m.get_message();
+            m.get_region_id() != self.region_id || msg.get_msg_type() != MessageType::MsgAppend
+                || msg.get_entries()
+                    .iter()
+                    .all(|e| e.get_index() < self.lo || e.get_index() > self.hi)
+        });
+        check_messages(msgs)
+    }
+}
+
+/// `DropVotesInTermFilter` drops the vote requests of the given term.
+#[derive(Clone)]
+pub struct DropVotesInTermFilter {
+    term: u64,
+}
+
+impl DropVotesInTermFilter {
+    pub fn new(term: u64) -> DropVotesInTermFilter {
+        DropVotesInTermFilter { term: term }
+    }
+}
+
+impl Filter<RaftMessage> for DropVotesInTermFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        msgs.retain(|m| {
+            let msg = m.get_message();
+            msg.get_msg_type() != MessageType::MsgRequestVote || msg.get_term() != self.term
+        });
+        check_messages(msgs)
+    }
+}
+
+type TraceKey = (u64, u64, u64, MessageType);
+
+fn trace_key(m: &TappedMessage) -> TraceKey {
+    (m.region_id, m.from_store_id, m.to_store_id, m.msg_type)
+}
+
+/// `ReplayFilter` applies the decisions of a recorded trace, see
+/// `SimulateTransport::record`, to the raft messages sent again. The n-th
+/// message of a region between two stores with a message type gets the
+/// decision of the n-th such message in the trace, including its delay. The
+/// part of the delay the sender was blocked for blocks the sender again, the
+/// rest is given by `delay`. Messages beyond the trace pass through.
+pub struct ReplayFilter {
+    decisions: Mutex<HashMap<TraceKey, VecDeque<TraceEntry>>>,
+    delays: Mutex<HashMap<TraceKey, time::Duration>>,
+}
+
+impl ReplayFilter {
+    pub fn new(trace: Vec<TraceEntry>) -> ReplayFilter {
+        let mut decisions = HashMap::new();
+        for entry in trace {
+            decisions
+                .entry(trace_key(&entry.msg))
+                .or_insert_with(VecDeque::new)
+                .push_back(entry);
+        }
+        ReplayFilter {
+            decisions: Mutex::new(decisions),
+            delays: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for ReplayFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut decisions = self.decisions.lock().unwrap();
+        let mut delays = self.delays.lock().unwrap();
+        let zero = time::Duration::from_millis(0);
+        let mut to_send = vec![];
+        for m in msgs.drain(..) {
+            let key = trace_key(&tap_raft_message(Direction::Send, &m));
+            let (decision, delay, blocked) =
+                match decisions.get_mut(&key).and_then(|d| d.pop_front()) {
+                    Some(entry) => (entry.decision, entry.delay, entry.blocked),
+                    None => {
+                        warn!("message {:?} is not in the trace, pass it", key);
+                        (Decision::Passed, None, zero)
+                    }
+                };
+            if blocked > zero {
+                block_sender(blocked);
+            }
+            match delay.and_then(|d| d.checked_sub(blocked)) {
+                Some(delay) if delay > zero => delays.insert(key, delay),
+                _ => delays.remove(&key),
+            };
+            match decision {
+                Decision::Dropped => continue,
+                Decision::Duplicated => to_send.push(m.clone()),
+                Decision::Passed | Decision::Delayed | Decision::Held => {}
+            }
+            to_send.push(m);
+        }
+        msgs.extend(to_send);
+        check_messages(msgs)
+    }
+
+    fn delay(&self, m: &RaftMessage) -> Option<time::Duration> {
+        let key = trace_key(&tap_raft_message(Direction::Send, m));
+        self.delays.lock().unwrap().get(&key).cloned()
+    }
+}
+
+pub fn is_heartbeat(m: &RaftMessage) -> bool {
+    match m.get_message().get_msg_type() {
+        MessageType::MsgHeartbeat | MessageType::MsgHeartbeatResponse => true,
+        _ => false,
+    }
+}
+
+/// `HeartbeatReorderFilter` buffers all non-heartbeat messages and shuffles them,
+/// but flushes the whole buffer ahead of every heartbeat that passes. So messages
+/// are only reordered within the interval between two heartbeats, like a transport
+/// that flushes on heartbeats.
+#[derive(Default)]
+pub struct HeartbeatReorderFilter {
+    buffered: Mutex<Vec<RaftMessage>>,
+    rng: FilterRng,
+}
+
+impl HeartbeatReorderFilter {
+    pub fn seed(mut self, seed: usize) -> HeartbeatReorderFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+}
+
+impl Filter<RaftMessage> for HeartbeatReorderFilter {
     fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
-        msgs.retain(|m| {
-            let region_id = m.get_region_id();
-            let from_store_id = m.get_from_peer().get_store_id();
-            let to_store_id = m.get_to_peer().get_store_id();
+        let mut buffered = self.buffered.lock().unwrap();
+        let mut to_send = vec![];
+        for m in msgs.drain(..) {
+            if is_heartbeat(&m) {
+                self.rng.shuffle(&mut buffered);
+                to_send.extend(buffered.drain(..));
+                to_send.push(m);
+            } else {
+                buffered.push(m);
+            }
+        }
+        msgs.extend(to_send);
+        Ok(())
+    }
 
-            if self.region_id == region_id &&
-                (self.direction.is_send() && self.store_id == from_store_id ||
-                    self.direction.is_recv() && self.store_id == to_store_id) &&
-                self.msg_type
-                    .as_ref()
-                    .map_or(true, |t| t == &m.get_message().get_msg_type())
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.buffered.lock().unwrap().drain(..).collect()
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
+}
+
+/// `HeartbeatDelayFilter` delays heartbeats and their responses, like a peer
+/// whose clock is skewed, while the other messages pass right away. Heartbeats
+/// are delivered in the background so log replication isn't held up behind
+/// them. The delay is shared by the clones and can be changed while installed.
+#[derive(Clone)]
+pub struct HeartbeatDelayFilter {
+    delay_ms: Arc<AtomicUsize>,
+}
+
+impl HeartbeatDelayFilter {
+    pub fn new(delay: time::Duration) -> HeartbeatDelayFilter {
+        HeartbeatDelayFilter {
+            delay_ms: Arc::new(AtomicUsize::new(duration_to_ms(delay) as usize)),
+        }
+    }
+
+    /// Change the delay of the heartbeats sent from now on, zero stops delaying.
+    pub fn set_delay(&self, delay: time::Duration) {
+        self.delay_ms
+            .store(duration_to_ms(delay) as usize, Ordering::SeqCst);
+    }
+}
+
+impl Filter<RaftMessage> for HeartbeatDelayFilter {
+    fn before(&self, _: &mut Vec<RaftMessage>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delay(&self, m: &RaftMessage) -> Option<time::Duration> {
+        let delay_ms = self.delay_ms.load(Ordering::SeqCst);
+        if delay_ms == 0 || !is_heartbeat(m) {
+            return None;
+        }
+        Some(time::Duration::from_millis(delay_ms as u64))
+    }
+}
+
+/// `TimeWindowFilter` scopes the inner filter to a window of time measured from
+/// its creation, the inner filter is inert before `start` and after `end`.
+pub struct TimeWindowFilter<M> {
+    inner: Box<Filter<M>>,
+    start: time::Duration,
+    end: time::Duration,
+    created: time::Instant,
+}
+
+impl<M> TimeWindowFilter<M> {
+    pub fn new(
+        inner: Box<Filter<M>>,
+        start: time::Duration,
+        end: time::Duration,
+    ) -> TimeWindowFilter<M> {
+        TimeWindowFilter {
+            inner: inner,
+            start: start,
+            end: end,
+            created: time::Instant::now(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        let elapsed = self.created.elapsed();
+        self.start <= elapsed && elapsed < self.end
+    }
+}
+
+impl<M> Filter<M> for TimeWindowFilter<M> {
+    fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
+        self.before_scoped(msgs).0
+    }
+
+    fn before_scoped(&self, msgs: &mut Vec<M>) -> (Result<()>, bool) {
+        if self.is_active() {
+            return self.inner.before_scoped(msgs);
+        }
+        (Ok(()), false)
+    }
+
+    fn after_scoped(&self, applied: bool, res: Result<()>) -> Result<()> {
+        // The window may have closed since `before`, what matters is whether
+        // the inner filter saw the messages.
+        if applied {
+            return self.inner.after_scoped(true, res);
+        }
+        res
+    }
+
+    fn drain(&self) -> Vec<M> {
+        self.inner.drain()
+    }
+
+    fn delay(&self, m: &M) -> Option<time::Duration> {
+        if self.is_active() {
+            return self.inner.delay(m);
+        }
+        None
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.inner.reseed(node_id);
+    }
+}
+
+/// `FreezeCommitFilter` keeps one follower of a region at a frozen commit index,
+/// so it keeps serving stale data. Appends to it carrying a newer commit index are
+/// dropped, while heartbeats are rewritten to carry the frozen index so the
+/// follower stays alive and doesn't start an election. Everything passes once
+/// `released` is set.
+#[derive(Clone)]
+pub struct FreezeCommitFilter {
+    region_id: u64,
+    store_id: u64,
+    commit: u64,
+    released: Arc<AtomicBool>,
+}
+
+impl FreezeCommitFilter {
+    pub fn new(
+        region_id: u64,
+        store_id: u64,
+        commit: u64,
+        released: Arc<AtomicBool>,
+    ) -> FreezeCommitFilter {
+        FreezeCommitFilter {
+            region_id: region_id,
+            store_id: store_id,
+            commit: commit,
+            released: released,
+        }
+    }
+}
+
+impl Filter<RaftMessage> for FreezeCommitFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        if self.released.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        msgs.retain(|m| {
+            m.get_region_id() != self.region_id || m.get_to_peer().get_store_id() != self.store_id ||
+                m.get_message().get_msg_type() != MessageType::MsgAppend ||
+                m.get_message().get_commit() <= self.commit
+        });
+        for m in msgs.iter_mut() {
+            if m.get_region_id() == self.region_id &&
+                m.get_to_peer().get_store_id() == self.store_id &&
+                m.get_message().get_msg_type() == MessageType::MsgHeartbeat &&
+                m.get_message().get_commit() > self.commit
             {
-                return match self.block {
-                    Either::Left(ref count) => loop {
-                        let left = count.load(Ordering::SeqCst);
-                        if left == 0 {
-                            return false;
-                        }
-                        if count.compare_and_swap(left, left - 1, Ordering::SeqCst) == left {
-                            return true;
-                        }
-                    },
-                    Either::Right(ref block) => !block.load(Ordering::SeqCst),
-                };
+                m.mut_message().set_commit(self.commit);
             }
-            true
+        }
+        check_messages(msgs)
+    }
+}
+
+/// `DropFirstNFilter` drops the first `n` messages of the given type and passes
+/// everything afterwards, like a peer coming online late. The count is shared
+/// by all destinations unless `per_destination` is set, and by the clones, so
+/// a `CloneFilterFactory` drops the first `n` messages of the whole cluster.
+#[derive(Clone)]
+pub struct DropFirstNFilter {
+    msg_type: MessageType,
+    n: usize,
+    per_destination: bool,
+    counts: Arc<Mutex<HashMap<u64, usize>>>,
+}
+
+impl DropFirstNFilter {
+    pub fn new(msg_type: MessageType, n: usize) -> DropFirstNFilter {
+        DropFirstNFilter {
+            msg_type: msg_type,
+            n: n,
+            per_destination: false,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Drop the first `n` messages to each store instead.
+    pub fn per_destination(mut self) -> DropFirstNFilter {
+        self.per_destination = true;
+        self
+    }
+
+    /// How many messages have been dropped so far.
+    pub fn dropped(&self) -> usize {
+        self.counts
+            .lock()
+            .unwrap()
+            .values()
+            .map(|&c| cmp::min(c, self.n))
+            .sum()
+    }
+}
+
+impl Filter<RaftMessage> for DropFirstNFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut counts = self.counts.lock().unwrap();
+        msgs.retain(|m| {
+            if m.get_message().get_msg_type() != self.msg_type {
+                return true;
+            }
+            let to_store_id = if self.per_destination {
+                m.get_to_peer().get_store_id()
+            } else {
+                0
+            };
+            let count = counts.entry(to_store_id).or_insert(0);
+            *count += 1;
+            *count > self.n
+        });
+        check_messages(msgs)
+    }
+}
+
+/// `UnreachableFilter` drops the messages to the store and reports the peers
+/// unreachable through `reporter`, the router of the sending store, like the
+/// real transport does when it fails to send.
+pub struct UnreachableFilter<R: RaftStoreRouter> {
+    store_id: u64,
+    reporter: Mutex<R>,
+}
+
+impl<R: RaftStoreRouter> UnreachableFilter<R> {
+    pub fn new(store_id: u64, reporter: R) -> UnreachableFilter<R> {
+        UnreachableFilter {
+            store_id: store_id,
+            reporter: Mutex::new(reporter),
+        }
+    }
+}
+
+impl<R: RaftStoreRouter> Filter<RaftMessage> for UnreachableFilter<R> {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let reporter = self.reporter.lock().unwrap();
+        msgs.retain(|m| {
+            let to_peer = m.get_to_peer();
+            if to_peer.get_store_id() != self.store_id {
+                return true;
+            }
+            let region_id = m.get_region_id();
+            if let Err(e) = reporter.report_unreachable(region_id, to_peer.get_id()) {
+                error!("failed to report unreachable {:?}: {:?}", to_peer, e);
+            }
+            false
         });
         check_messages(msgs)
     }
 }
 
-impl RegionPacketFilter {
-    pub fn new(region_id: u64, store_id: u64) -> RegionPacketFilter {
-        RegionPacketFilter {
-            region_id: region_id,
-            store_id: store_id,
-            direction: Direction::Both,
+impl<R: RaftStoreRouter> Clone for UnreachableFilter<R> {
+    fn clone(&self) -> UnreachableFilter<R> {
+        UnreachableFilter::new(self.store_id, self.reporter.lock().unwrap().clone())
+    }
+}
+
+/// `DuplicateVoteFilter` duplicates `MsgRequestVote` and `MsgRequestVoteResponse`
+/// at the given rate, leaving the other messages untouched.
+#[derive(Clone)]
+pub struct DuplicateVoteFilter {
+    rate: u32,
+    rng: FilterRng,
+}
+
+impl DuplicateVoteFilter {
+    pub fn new(rate: u32) -> DuplicateVoteFilter {
+        DuplicateVoteFilter {
+            rate: rate,
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> DuplicateVoteFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+}
+
+impl Filter<RaftMessage> for DuplicateVoteFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut dups = vec![];
+        for m in msgs.iter() {
+            match m.get_message().get_msg_type() {
+                MessageType::MsgRequestVote | MessageType::MsgRequestVoteResponse => {
+                    if self.rng.next_u32() % 100u32 < self.rate {
+                        dups.push(m.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        msgs.extend(dups);
+        Ok(())
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
+}
+
+fn is_response(msg_type: MessageType) -> bool {
+    match msg_type {
+        MessageType::MsgAppendResponse |
+        MessageType::MsgHeartbeatResponse |
+        MessageType::MsgRequestVoteResponse |
+        MessageType::MsgRequestPreVoteResponse |
+        MessageType::MsgReadIndexResp => true,
+        _ => false,
+    }
+}
+
+/// Raft messages are either requests or responses to them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Traffic {
+    Request,
+    Response,
+}
+
+impl Traffic {
+    pub fn of(msg_type: MessageType) -> Traffic {
+        if is_response(msg_type) {
+            Traffic::Response
+        } else {
+            Traffic::Request
+        }
+    }
+}
+
+/// `HoldRequestFilter` holds request messages to the given store for `hold`,
+/// while responses go through at once, so responses sent later overtake the
+/// requests like a reordering proxy would do. Held requests are sent with the
+/// first messages after their hold time elapses.
+pub struct HoldRequestFilter {
+    store_id: u64,
+    hold: time::Duration,
+    held: Mutex<Vec<(time::Instant, RaftMessage)>>,
+}
+
+impl HoldRequestFilter {
+    pub fn new(store_id: u64, hold: time::Duration) -> HoldRequestFilter {
+        HoldRequestFilter {
+            store_id: store_id,
+            hold: hold,
+            held: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Filter<RaftMessage> for HoldRequestFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut held = self.held.lock().unwrap();
+        let now = time::Instant::now();
+        let mut to_send = vec![];
+        for m in msgs.drain(..) {
+            if m.get_to_peer().get_store_id() == self.store_id &&
+                !is_response(m.get_message().get_msg_type())
+            {
+                held.push((now + self.hold, m));
+            } else {
+                to_send.push(m);
+            }
+        }
+        let (ready, pending): (Vec<_>, Vec<_>) = held.drain(..).partition(|&(t, _)| t <= now);
+        *held = pending;
+        to_send.extend(ready.into_iter().map(|(_, m)| m));
+        msgs.extend(to_send);
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.held.lock().unwrap().drain(..).map(|(_, m)| m).collect()
+    }
+}
+
+impl Clone for HoldRequestFilter {
+    fn clone(&self) -> HoldRequestFilter {
+        HoldRequestFilter::new(self.store_id, self.hold)
+    }
+}
+
+/// `OutOrderFilter` buffers up to `capacity` messages and then sends them
+/// all in a shuffled order. Messages still buffered are delivered when the
+/// filter is removed or the transport is dropped.
+pub struct OutOrderFilter<M> {
+    capacity: usize,
+    buffered: Mutex<Vec<M>>,
+    rng: FilterRng,
+}
+
+impl<M> OutOrderFilter<M> {
+    pub fn new(capacity: usize) -> OutOrderFilter<M> {
+        OutOrderFilter {
+            capacity: capacity,
+            buffered: Mutex::new(vec![]),
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> OutOrderFilter<M> {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+}
+
+impl<M: Send> Filter<M> for OutOrderFilter<M> {
+    fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
+        let mut buffered = self.buffered.lock().unwrap();
+        buffered.extend(msgs.drain(..));
+        if buffered.len() >= self.capacity {
+            self.rng.shuffle(&mut buffered);
+            msgs.extend(buffered.drain(..));
+        }
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<M> {
+        self.buffered.lock().unwrap().drain(..).collect()
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
+}
+
+impl<M> Clone for OutOrderFilter<M> {
+    fn clone(&self) -> OutOrderFilter<M> {
+        OutOrderFilter {
+            rng: self.rng.clone(),
+            ..OutOrderFilter::new(self.capacity)
+        }
+    }
+}
+
+/// The request type a response type answers.
+pub fn request_of(msg_type: MessageType) -> Option<MessageType> {
+    match msg_type {
+        MessageType::MsgAppendResponse => Some(MessageType::MsgAppend),
+        MessageType::MsgHeartbeatResponse => Some(MessageType::MsgHeartbeat),
+        MessageType::MsgRequestVoteResponse => Some(MessageType::MsgRequestVote),
+        MessageType::MsgRequestPreVoteResponse => Some(MessageType::MsgRequestPreVote),
+        MessageType::MsgReadIndexResp => Some(MessageType::MsgReadIndex),
+        _ => None,
+    }
+}
+
+/// Whether `resp` may answer `req`: it's a response of the region from the peer
+/// `req` is sent to, back to the sender, and answers the type of `req`.
+fn may_answer(resp: &RaftMessage, req: &RaftMessage) -> bool {
+    request_of(resp.get_message().get_msg_type()) == Some(req.get_message().get_msg_type()) &&
+        resp.get_region_id() == req.get_region_id() &&
+        resp.get_from_peer().get_id() == req.get_to_peer().get_id() &&
+        resp.get_to_peer().get_id() == req.get_from_peer().get_id()
+}
+
+/// `CausalReorderFilter` buffers up to `capacity` messages and sends them in a
+/// shuffled order like `OutOrderFilter`, except that a response never goes
+/// ahead of a request buffered before it that it may answer, see `may_answer`.
+///
+/// A request and its response leave on the transports of different stores, so
+/// clones share the buffer, and the messages are sent by whichever transport
+/// fills it. Snapshots aren't buffered, only the store which built one can
+/// send it.
+pub struct CausalReorderFilter {
+    capacity: usize,
+    buffered: Arc<Mutex<Vec<RaftMessage>>>,
+    rng: FilterRng,
+}
+
+impl CausalReorderFilter {
+    pub fn new(capacity: usize) -> CausalReorderFilter {
+        CausalReorderFilter {
+            capacity: capacity,
+            buffered: Arc::new(Mutex::new(vec![])),
+            rng: FilterRng::default(),
+        }
+    }
+
+    pub fn seed(mut self, seed: usize) -> CausalReorderFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+}
+
+impl Filter<RaftMessage> for CausalReorderFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut buffered = self.buffered.lock().unwrap();
+        let (snaps, rest): (Vec<_>, Vec<_>) = msgs.drain(..)
+            .partition(|m| m.get_message().get_msg_type() == MessageType::MsgSnapshot);
+        msgs.extend(snaps);
+        buffered.extend(rest);
+        if buffered.len() < self.capacity {
+            return Ok(());
+        }
+        while !buffered.is_empty() {
+            let ready: Vec<_> = (0..buffered.len())
+                .filter(|&i| !buffered[..i].iter().any(|req| may_answer(&buffered[i], req)))
+                .collect();
+            let i = ready[self.rng.next_u32() as usize % ready.len()];
+            msgs.push(buffered.remove(i));
+        }
+        Ok(())
+    }
+
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.buffered.lock().unwrap().drain(..).collect()
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
+}
+
+impl Clone for CausalReorderFilter {
+    fn clone(&self) -> CausalReorderFilter {
+        CausalReorderFilter {
+            capacity: self.capacity,
+            buffered: self.buffered.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+/// `ScopedFilter` applies `inner` only to the messages matching all the given
+/// conditions; the other messages pass through untouched.
+#[derive(Clone)]
+pub struct ScopedFilter<F> {
+    inner: F,
+    msg_type: Option<MessageType>,
+    region_id: Option<u64>,
+    to_store_id: Option<u64>,
+    traffic: Option<Traffic>,
+}
+
+impl<F: Filter<RaftMessage>> ScopedFilter<F> {
+    pub fn new(inner: F) -> ScopedFilter<F> {
+        ScopedFilter {
+            inner: inner,
             msg_type: None,
-            block: Either::Right(Arc::new(AtomicBool::new(true))),
+            region_id: None,
+            to_store_id: None,
+            traffic: None,
         }
     }
 
-    pub fn direction(mut self, direction: Direction) -> RegionPacketFilter {
-        self.direction = direction;
+    pub fn msg_type(mut self, m_type: MessageType) -> ScopedFilter<F> {
+        self.msg_type = Some(m_type);
         self
     }
 
-    pub fn msg_type(mut self, m_type: MessageType) -> RegionPacketFilter {
-        self.msg_type = Some(m_type);
+    pub fn region(mut self, region_id: u64) -> ScopedFilter<F> {
+        self.region_id = Some(region_id);
         self
     }
 
-    pub fn allow(mut self, number: usize) -> RegionPacketFilter {
-        self.block = Either::Left(Arc::new(AtomicUsize::new(number)));
+    pub fn to_store(mut self, store_id: u64) -> ScopedFilter<F> {
+        self.to_store_id = Some(store_id);
         self
     }
 
-    pub fn when(mut self, condition: Arc<AtomicBool>) -> RegionPacketFilter {
-        self.block = Either::Right(condition);
+    /// Only match requests or only responses.
+    pub fn traffic(mut self, traffic: Traffic) -> ScopedFilter<F> {
+        self.traffic = Some(traffic);
         self
     }
-}
 
-#[derive(Default)]
-pub struct SnapshotFilter {
-    drop: AtomicBool,
+    fn is_matched(&self, m: &RaftMessage) -> bool {
+        let msg_type = m.get_message().get_msg_type();
+        self.msg_type.map_or(true, |t| t == msg_type) &&
+            self.region_id.map_or(true, |id| id == m.get_region_id()) &&
+            self.to_store_id
+                .map_or(true, |id| id == m.get_to_peer().get_store_id()) &&
+            self.traffic.map_or(true, |t| t == Traffic::of(msg_type))
+    }
 }
 
-impl Filter<RaftMessage> for SnapshotFilter {
+impl<F: Filter<RaftMessage>> Filter<RaftMessage> for ScopedFilter<F> {
     fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
-        msgs.retain(|m| {
-            m.get_message().get_msg_type() != MessageType::MsgSnapshot
-        });
-        self.drop.store(msgs.is_empty(), Ordering::Relaxed);
-        check_messages(msgs)
+        self.before_scoped(msgs).0
     }
 
-    fn after(&self, x: Result<()>) -> Result<()> {
-        if self.drop.load(Ordering::Relaxed) {
-            Ok(())
-        } else {
-            x
+    fn before_scoped(&self, msgs: &mut Vec<RaftMessage>) -> (Result<()>, bool) {
+        let (mut matched, rest): (Vec<_>, Vec<_>) =
+            msgs.drain(..).partition(|m| self.is_matched(m));
+        msgs.extend(rest);
+        if matched.is_empty() {
+            return (check_messages(msgs), false);
+        }
+        // The inner filter may be scoped too, it only applies if it says so.
+        let (res, applied) = self.inner.before_scoped(&mut matched);
+        if res.is_ok() {
+            msgs.extend(matched);
         }
+        (check_messages(msgs), applied)
     }
-}
-
-/// `CollectSnapshotFilter` is a simulation transport filter to simulate the simultaneous delivery
-/// of multiple snapshots from different peers. It collects the snapshots from different
-/// peers and drop the subsequent snapshots from the same peers. Currently, if there are
-/// more than 1 snapshots in this filter, all the snapshots will be dilivered at once.
-pub struct CollectSnapshotFilter {
-    dropped: AtomicBool,
-    stale: AtomicBool,
-    pending_msg: Mutex<HashMap<u64, StoreMsg>>,
-    pending_count_sender: Mutex<Sender<usize>>,
-}
 
-impl CollectSnapshotFilter {
-    pub fn new(sender: Sender<usize>) -> CollectSnapshotFilter {
-        CollectSnapshotFilter {
-            dropped: AtomicBool::new(false),
-            stale: AtomicBool::new(false),
-            pending_msg: Mutex::new(HashMap::new()),
-            pending_count_sender: Mutex::new(sender),
+    fn after_scoped(&self, applied: bool, res: Result<()>) -> Result<()> {
+        if applied {
+            return self.inner.after_scoped(true, res);
         }
+        res
     }
-}
 
-impl Filter<StoreMsg> for CollectSnapshotFilter {
-    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
-        if self.stale.load(Ordering::Relaxed) {
-            return Ok(());
-        }
-        let mut to_send = vec![];
-        let mut pending_msg = self.pending_msg.lock().unwrap();
-        for m in msgs.drain(..) {
-            let (is_pending, from_peer_id) = match m {
-                StoreMsg::RaftMessage(ref msg) => {
-                    if msg.get_message().get_msg_type() == MessageType::MsgSnapshot {
-                        let from_peer_id = msg.get_from_peer().get_id();
-                        if pending_msg.contains_key(&from_peer_id) {
-                            // Drop this snapshot message directly since it's from a seen peer
-                            continue;
-                        } else {
-                            // Pile the snapshot from unseen peer
-                            (true, from_peer_id)
-                        }
-                    } else {
-                        (false, 0)
-                    }
-                }
-                _ => (false, 0),
-            };
-            if is_pending {
-                self.dropped
-                    .compare_and_swap(false, true, Ordering::Relaxed);
-                pending_msg.insert(from_peer_id, m);
-                let sender = self.pending_count_sender.lock().unwrap();
-                sender.send(pending_msg.len()).unwrap();
-            } else {
-                to_send.push(m);
-            }
-        }
-        // Deliver those pending snapshots if there are more than 1.
-        if pending_msg.len() > 1 {
-            self.dropped
-                .compare_and_swap(true, false, Ordering::Relaxed);
-            msgs.extend(pending_msg.drain().map(|(_, v)| v));
-            self.stale.compare_and_swap(false, true, Ordering::Relaxed);
-        }
-        msgs.extend(to_send);
-        check_messages(msgs)
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.inner.drain()
     }
 
-    fn after(&self, res: Result<()>) -> Result<()> {
-        if res.is_err() && self.dropped.load(Ordering::Relaxed) {
-            self.dropped
-                .compare_and_swap(true, false, Ordering::Relaxed);
-            Ok(())
-        } else {
-            res
+    fn delay(&self, m: &RaftMessage) -> Option<time::Duration> {
+        if self.is_matched(m) {
+            return self.inner.delay(m);
         }
+        None
+    }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.inner.reseed(node_id);
     }
 }
 
-pub struct DropSnapshotFilter {
-    notifier: Mutex<Sender<u64>>,
+/// `GapRecorderFilter` records the time between consecutive messages on each
+/// link, keyed by (from store, to store). Clones share the records.
+#[derive(Clone, Default)]
+pub struct GapRecorderFilter {
+    links: Arc<Mutex<HashMap<(u64, u64), (time::Instant, Vec<time::Duration>)>>>,
 }
 
-impl DropSnapshotFilter {
-    pub fn new(ch: Sender<u64>) -> DropSnapshotFilter {
-        DropSnapshotFilter {
-            notifier: Mutex::new(ch),
-        }
+impl GapRecorderFilter {
+    /// Returns the gaps recorded on the link from `from` to `to`.
+    pub fn gaps(&self, from: u64, to: u64) -> Vec<time::Duration> {
+        let links = self.links.lock().unwrap();
+        links
+            .get(&(from, to))
+            .map_or_else(Vec::new, |&(_, ref gaps)| gaps.clone())
     }
 }
 
-impl Filter<StoreMsg> for DropSnapshotFilter {
-    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
-        let notifier = self.notifier.lock().unwrap();
-        msgs.retain(|m| match *m {
-            StoreMsg::RaftMessage(ref msg) => {
-                if msg.get_message().get_msg_type() != MessageType::MsgSnapshot {
-                    true
-                } else {
-                    let idx = msg.get_message().get_snapshot().get_metadata().get_index();
-                    if let Err(e) = notifier.send(idx) {
-                        error!("failed to notify snapshot {:?}: {:?}", msg, e);
-                    }
-                    false
-                }
+impl Filter<RaftMessage> for GapRecorderFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut links = self.links.lock().unwrap();
+        for m in msgs.iter() {
+            let now = time::Instant::now();
+            let link = (m.get_from_peer().get_store_id(), m.get_to_peer().get_store_id());
+            if let Some(&mut (ref mut last, ref mut gaps)) = links.get_mut(&link) {
+                gaps.push(now.duration_since(*last));
+                *last = now;
+                continue;
             }
-            _ => true,
-        });
+            links.insert(link, (now, vec![]));
+        }
         Ok(())
     }
 }
 
-/// Filter leading duplicated Snap.
-///
-/// It will pause the first snapshot and fiter out all the snapshot that
-/// are same as first snapshot msg until the first different snapshot shows up.
-pub struct LeadingDuplicatedSnapshotFilter {
-    dropped: AtomicBool,
-    stale: Arc<AtomicBool>,
-    last_msg: Mutex<Option<RaftMessage>>,
+/// `SlowFollowerRampFilter` models a degrading follower: messages to the store
+/// are held for a latency starting at `start`, which grows by `step` every
+/// `step_interval` until it reaches `max`. Other messages aren't delayed, and
+/// held messages are sent with the first messages after their latency elapses.
+pub struct SlowFollowerRampFilter {
+    store_id: u64,
+    start: time::Duration,
+    step: time::Duration,
+    step_interval: time::Duration,
+    max: time::Duration,
+    created: time::Instant,
+    held: Mutex<Vec<(time::Instant, RaftMessage)>>,
 }
 
-impl LeadingDuplicatedSnapshotFilter {
-    pub fn new(stale: Arc<AtomicBool>) -> LeadingDuplicatedSnapshotFilter {
-        LeadingDuplicatedSnapshotFilter {
-            dropped: AtomicBool::new(false),
-            stale: stale,
-            last_msg: Mutex::new(None),
+impl SlowFollowerRampFilter {
+    pub fn new(
+        store_id: u64,
+        start: time::Duration,
+        step: time::Duration,
+        step_interval: time::Duration,
+        max: time::Duration,
+    ) -> SlowFollowerRampFilter {
+        assert!(step_interval > time::Duration::from_millis(0));
+        assert!(start <= max, "{:?} > {:?}", start, max);
+        SlowFollowerRampFilter {
+            store_id: store_id,
+            start: start,
+            step: step,
+            step_interval: step_interval,
+            max: max,
+            created: time::Instant::now(),
+            held: Mutex::new(vec![]),
         }
     }
+
+    /// Returns the latency of the follower at the moment.
+    pub fn latency(&self) -> time::Duration {
+        let steps =
+            duration_to_nanos(self.created.elapsed()) / duration_to_nanos(self.step_interval);
+        let steps = cmp::min(steps, u64::from(u32::MAX)) as u32;
+        self.step
+            .checked_mul(steps)
+            .and_then(|ramp| self.start.checked_add(ramp))
+            .map_or(self.max, |latency| cmp::min(latency, self.max))
+    }
 }
 
-impl Filter<StoreMsg> for LeadingDuplicatedSnapshotFilter {
-    fn before(&self, msgs: &mut Vec<StoreMsg>) -> Result<()> {
-        let mut last_msg = self.last_msg.lock().unwrap();
-        let mut stale = self.stale.load(Ordering::Relaxed);
-        if stale {
-            if last_msg.is_some() {
-                msgs.push(StoreMsg::RaftMessage(last_msg.take().unwrap()));
-            }
-            return check_messages(msgs);
-        }
+impl Filter<RaftMessage> for SlowFollowerRampFilter {
+    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
+        let mut held = self.held.lock().unwrap();
+        let now = time::Instant::now();
+        let latency = self.latency();
         let mut to_send = vec![];
         for m in msgs.drain(..) {
-            match m {
-                StoreMsg::RaftMessage(msg) => {
-                    if msg.get_message().get_msg_type() == MessageType::MsgSnapshot && !stale {
-                        if last_msg.as_ref().map_or(false, |l| l != &msg) {
-                            to_send.push(StoreMsg::RaftMessage(last_msg.take().unwrap()));
-                            *last_msg = Some(msg);
-                            stale = true;
-                        } else {
-                            self.dropped.store(true, Ordering::Relaxed);
-                            *last_msg = Some(msg);
-                        }
-                    } else {
-                        to_send.push(StoreMsg::RaftMessage(msg));
-                    }
-                }
-                _ => {
-                    to_send.push(m);
-                }
+            if m.get_to_peer().get_store_id() == self.store_id {
+                held.push((now + latency, m));
+            } else {
+                to_send.push(m);
             }
         }
-        self.stale.store(stale, Ordering::Relaxed);
+        let (ready, pending): (Vec<_>, Vec<_>) = held.drain(..).partition(|&(t, _)| t <= now);
+        *held = pending;
+        to_send.extend(ready.into_iter().map(|(_, m)| m));
         msgs.extend(to_send);
-        check_messages(msgs)
+        Ok(())
     }
 
-    fn after(&self, res: Result<()>) -> Result<()> {
-        let dropped = self.dropped
-            .compare_and_swap(true, false, Ordering::Relaxed);
-        if res.is_err() && dropped {
-            Ok(())
-        } else {
-            res
-        }
+    fn drain(&self) -> Vec<RaftMessage> {
+        self.held.lock().unwrap().drain(..).map(|(_, m)| m).collect()
     }
 }
 
-/// `RandomLatencyFilter` is a transport filter to simulate randomized network latency.
-/// Based on a randomized rate, `RandomLatencyFilter` will decide whether to delay
-/// the sending of any message. It's could be used to simulate the message sending
-/// in a network with random latency, where messages could be delayed, disordered or lost.
-pub struct RandomLatencyFilter {
-    delay_rate: u32,
-    delayed_msgs: Mutex<Vec<RaftMessage>>,
+/// `CongestionDelayFilter` models congestion on a link: each message is delayed
+/// by `per_inflight` times the number of messages in flight including itself,
+/// so the latency grows as the link backs up and recovers once it drains.
+/// Messages are delivered in the background like `AsyncDelayFilter`.
+pub struct CongestionDelayFilter {
+    per_inflight: time::Duration,
+    inflight: Mutex<Vec<time::Instant>>,
 }
 
-impl RandomLatencyFilter {
-    pub fn new(rate: u32) -> RandomLatencyFilter {
-        RandomLatencyFilter {
-            delay_rate: rate,
-            delayed_msgs: Mutex::new(vec![]),
+impl CongestionDelayFilter {
+    pub fn new(per_inflight: time::Duration) -> CongestionDelayFilter {
+        CongestionDelayFilter {
+            per_inflight: per_inflight,
+            inflight: Mutex::new(vec![]),
         }
     }
+}
+
+impl<M> Filter<M> for CongestionDelayFilter {
+    fn before(&self, _: &mut Vec<M>) -> Result<()> {
+        Ok(())
+    }
 
-    fn will_delay(&self, _: &RaftMessage) -> bool {
-        rand::random::<u32>() % 100u32 >= self.delay_rate
+    fn delay(&self, _: &M) -> Option<time::Duration> {
+        let mut inflight = self.inflight.lock().unwrap();
+        let now = time::Instant::now();
+        inflight.retain(|t| *t > now);
+        let delay = self.per_inflight * (inflight.len() as u32 + 1);
+        inflight.push(now + delay);
+        Some(delay)
     }
 }
 
-impl Filter<RaftMessage> for RandomLatencyFilter {
-    fn before(&self, msgs: &mut Vec<RaftMessage>) -> Result<()> {
-        let mut to_send = vec![];
-        let mut to_delay = vec![];
-        let mut delayed_msgs = self.delayed_msgs.lock().unwrap();
-        // check whether to send those messages which are delayed previouly
-        // and check whether to send any newly incoming message if they are not delayed
-        for m in delayed_msgs.drain(..).chain(msgs.drain(..)) {
-            if self.will_delay(&m) {
-                to_delay.push(m);
-            } else {
-                to_send.push(m);
-            }
+impl Clone for CongestionDelayFilter {
+    fn clone(&self) -> CongestionDelayFilter {
+        CongestionDelayFilter::new(self.per_inflight)
+    }
+}
+
+/// `FlakyLinkFilter` models a lossy link with high latency. Messages are dropped
+/// at `loss_rate` first, then the batch of survivors is delayed once for `base`
+/// plus a random jitter up to `jitter`, so dropped messages never cost a delay.
+pub struct FlakyLinkFilter {
+    loss_rate: u32,
+    base: time::Duration,
+    jitter: time::Duration,
+    rng: FilterRng,
+}
+
+impl FlakyLinkFilter {
+    pub fn new(loss_rate: u32, base: time::Duration, jitter: time::Duration) -> FlakyLinkFilter {
+        FlakyLinkFilter {
+            loss_rate: loss_rate,
+            base: base,
+            jitter: jitter,
+            rng: FilterRng::default(),
         }
-        delayed_msgs.extend(to_delay);
-        msgs.extend(to_send);
+    }
+
+    pub fn seed(mut self, seed: usize) -> FlakyLinkFilter {
+        self.rng = FilterRng::seeded(seed);
+        self
+    }
+}
+
+impl<M> Filter<M> for FlakyLinkFilter {
+    fn before(&self, msgs: &mut Vec<M>) -> Result<()> {
+        msgs.retain(|_| self.rng.next_u32() % 100u32 >= self.loss_rate);
+        check_messages(msgs)?;
+        let jitter_ms = duration_to_ms(self.jitter);
+        let jitter = u64::from(self.rng.next_u32()) % (jitter_ms + 1);
+        block_sender(self.base + time::Duration::from_millis(jitter));
         Ok(())
     }
+
+    fn reseed(&mut self, node_id: u64) {
+        self.rng.reseed(node_id);
+    }
 }
 
-impl Clone for RandomLatencyFilter {
-    fn clone(&self) -> RandomLatencyFilter {
-        let delayed_msgs = self.delayed_msgs.lock().unwrap();
-        RandomLatencyFilter {
-            delay_rate: self.delay_rate,
-            delayed_msgs: Mutex::new(delayed_msgs.clone()),
+impl Clone for FlakyLinkFilter {
+    fn clone(&self) -> FlakyLinkFilter {
+        FlakyLinkFilter {
+            loss_rate: self.loss_rate,
+            base: self.base,
+            jitter: self.jitter,
+            rng: self.rng.clone(),
         }
     }
 }